@@ -0,0 +1,325 @@
+use crate::data::StatRepo;
+use crate::utils::Unit;
+
+// Healers have no tenacity, their multiplier stays at its base value
+const TENACITY: u64 = 1000;
+const DIRECT_HIT_MULTIPLIER: u64 = 125;
+// Damage is rolled uniformly between 95% and 105% of its value
+pub const VARIANCE: std::ops::RangeInclusive<u32> = 95..=105;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DamageKind {
+    Direct,
+    // One tick of a damage over time effect
+    OverTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HitType {
+    pub critical: bool,
+    pub direct_hit: bool,
+}
+
+pub const HIT_TYPES: [HitType; 4] = [
+    HitType { critical: false, direct_hit: false },
+    HitType { critical: true, direct_hit: false },
+    HitType { critical: false, direct_hit: true },
+    HitType { critical: true, direct_hit: true },
+];
+
+// Stat multipliers of a stat line, worked out once for all the hits of a cast
+struct Multipliers {
+    attack_power: u64,
+    determination: u64,
+    weapon_damage: u64,
+    trait_bonus: u64,
+    spell_speed: u64,
+    critical: u64,
+}
+
+impl Multipliers {
+    fn new(stats: &impl StatRepo) -> Self {
+        Self {
+            attack_power: stats.magic_attack_power().0 as u64,
+            determination: stats.det_multiplier().0 as u64,
+            weapon_damage: stats.adjusted_weapon_damage().0 as u64,
+            trait_bonus: stats.trait_bonus().0 as u64,
+            spell_speed: stats.sps_multiplier().0 as u64,
+            critical: stats.crit_multiplier().0 as u64,
+        }
+    }
+
+    // Everything before the crit, direct hit and variance rolls. Potency, attack power and
+    // determination are floored once, damage over time gets the spell speed multiplier and one
+    // extra point of damage.
+    fn unrolled(&self, kind: DamageKind, potency: u32) -> u64 {
+        let damage = potency as u64 * self.attack_power * self.determination / 100 / 1000 * TENACITY / 1000;
+        match kind {
+            DamageKind::Direct => damage * self.weapon_damage / 100 * self.trait_bonus / 100,
+            DamageKind::OverTime => damage * self.spell_speed / 1000 * self.weapon_damage / 100 * self.trait_bonus / 100 + 1,
+        }
+    }
+
+    fn critical_and_direct_hit(&self, damage: u64, hit: HitType) -> u64 {
+        let critical = if hit.critical { self.critical } else { 1000 };
+        let direct_hit = if hit.direct_hit { DIRECT_HIT_MULTIPLIER } else { 100 };
+        damage * critical / 1000 * direct_hit / 100
+    }
+
+    // Direct damage gets its crit and direct hit multipliers before the variance, damage over time
+    // after it
+    fn rolled(&self, kind: DamageKind, unrolled: u64, hit: HitType, variance: u32) -> u64 {
+        match kind {
+            DamageKind::Direct => self.critical_and_direct_hit(unrolled, hit) * variance as u64 / 100,
+            DamageKind::OverTime => self.critical_and_direct_hit(unrolled * variance as u64 / 100, hit),
+        }
+    }
+}
+
+// What an action deals, and the hit types it always lands whatever the rates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Action {
+    pub kind: DamageKind,
+    pub potency: u32,
+    pub guaranteed: HitType,
+}
+
+impl Action {
+    pub const fn direct(potency: u32) -> Self {
+        Self {
+            kind: DamageKind::Direct,
+            potency,
+            guaranteed: HitType { critical: false, direct_hit: false },
+        }
+    }
+
+    pub const fn over_time(potency: u32) -> Self {
+        Self {
+            kind: DamageKind::OverTime,
+            ..Self::direct(potency)
+        }
+    }
+}
+
+// Buffs up when a hit lands. Damage buffs are in percent (105 for +5%) and floored one after the
+// other. Crit and direct hit buffs add to the rates, in thousandths (100 for Chain Stratagem's
+// +10%), they don't touch the stats.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Buffs {
+    pub damage: Vec<Unit<1, 100>>,
+    pub critical: Unit<1, 1000>,
+    pub direct_hit: Unit<1, 1000>,
+}
+
+// Crit and direct hit chances of a hit
+pub fn chances(stats: &impl StatRepo, action: &Action, buffs: &Buffs) -> (f64, f64) {
+    let critical = if action.guaranteed.critical {
+        1.0
+    } else {
+        ((stats.crit_rate().0 + buffs.critical.0) as f64 / 1000.0).min(1.0)
+    };
+    let direct_hit = if action.guaranteed.direct_hit {
+        1.0
+    } else {
+        ((stats.dh_rate().0 + buffs.direct_hit.0) as f64 / 1000.0).min(1.0)
+    };
+    (critical, direct_hit)
+}
+
+// A guaranteed crit or direct hit can't get any likelier, so rate buffs raise its damage instead,
+// by the rate times the bonus of a crit or direct hit, as one more multiplier
+fn guaranteed_bonuses(stats: &impl StatRepo, action: &Action, buffs: &Buffs) -> [u64; 2] {
+    let critical = if action.guaranteed.critical {
+        1000 + buffs.critical.0 as u64 * (stats.crit_multiplier().0 as u64 - 1000) / 1000
+    } else {
+        1000
+    };
+    let direct_hit = if action.guaranteed.direct_hit {
+        1000 + buffs.direct_hit.0 as u64 * (DIRECT_HIT_MULTIPLIER - 100) * 10 / 1000
+    } else {
+        1000
+    };
+    [critical, direct_hit]
+}
+
+fn buffed(damage: u64, bonuses: [u64; 2], buffs: &Buffs) -> u32 {
+    let damage = bonuses.iter().fold(damage, |damage, bonus| damage * bonus / 1000);
+    buffs.damage.iter().fold(damage, |damage, buff| damage * buff.0 as u64 / 100) as u32
+}
+
+// Damage of one hit, every multiplier floored in the order the game applies them. `variance` is
+// the roll in `VARIANCE`.
+pub fn damage(stats: &impl StatRepo, action: &Action, hit: HitType, variance: u32, buffs: &Buffs) -> u32 {
+    let multipliers = Multipliers::new(stats);
+    let unrolled = multipliers.unrolled(action.kind, action.potency);
+    let bonuses = guaranteed_bonuses(stats, action, buffs);
+    buffed(multipliers.rolled(action.kind, unrolled, hit, variance), bonuses, buffs)
+}
+
+// Average damage of a hit over the crit and direct hit chances and every variance roll
+pub fn expected_damage(stats: &impl StatRepo, action: &Action, buffs: &Buffs) -> f64 {
+    let multipliers = Multipliers::new(stats);
+    let unrolled = multipliers.unrolled(action.kind, action.potency);
+    let bonuses = guaranteed_bonuses(stats, action, buffs);
+    let (crit_rate, dh_rate) = chances(stats, action, buffs);
+    HIT_TYPES.iter()
+        .map(|hit| {
+            let crit_chance = if hit.critical { crit_rate } else { 1.0 - crit_rate };
+            let dh_chance = if hit.direct_hit { dh_rate } else { 1.0 - dh_rate };
+            if crit_chance * dh_chance == 0.0 {
+                return 0.0;
+            }
+            let total: u64 = VARIANCE
+                .map(|variance| buffed(multipliers.rolled(action.kind, unrolled, *hit, variance), bonuses, buffs) as u64)
+                .sum();
+            crit_chance * dh_chance * total as f64 / VARIANCE.count() as f64
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Stats;
+
+    // Attack power 1455, determination 1103, weapon damage 176, spell speed 1054, crit 1610
+    const STATS: Stats = Stats {
+        weapon_damage: 132,
+        mind: 3100,
+        vitality: 3000,
+        piety: 390,
+        direct_hit: 1200,
+        critical: 2400,
+        determination: 1800,
+        spell_speed: 1200,
+    };
+
+    const DOSIS: Action = Action::direct(330);
+
+    fn rate_buffs(critical: u32, direct_hit: u32) -> Buffs {
+        Buffs {
+            critical: Unit(critical),
+            direct_hit: Unit(direct_hit),
+            ..Default::default()
+        }
+    }
+
+    // Reference values worked out by hand from the damage formulas of the Allagan Studies, which is
+    // the same source the pipeline follows: they catch slips in the flooring order, not a wrong
+    // formula.
+    // TODO pin a few of these to in-game logs or a published worked example, and cite it
+    #[test]
+    fn dosis() {
+        let dosis = |hit, variance| damage(&STATS, &DOSIS, hit, variance, &Buffs::default());
+        assert_eq!(dosis(HIT_TYPES[0], 100), 12116);
+        assert_eq!(dosis(HIT_TYPES[1], 100), 19506);
+        assert_eq!(dosis(HIT_TYPES[2], 100), 15145);
+        assert_eq!(dosis(HIT_TYPES[3], 100), 24382);
+        assert_eq!(dosis(HIT_TYPES[0], 95), 11510);
+        assert_eq!(dosis(HIT_TYPES[3], 105), 25601);
+    }
+
+    #[test]
+    fn phlegma() {
+        let phlegma = |hit, variance| damage(&STATS, &Action::direct(600), hit, variance, &Buffs::default());
+        assert_eq!(phlegma(HIT_TYPES[0], 100), 22031);
+        assert_eq!(phlegma(HIT_TYPES[3], 100), 44336);
+    }
+
+    #[test]
+    fn eukrasian_dosis_tick() {
+        let tick = |hit, variance| damage(&STATS, &Action::over_time(70), hit, variance, &Buffs::default());
+        assert_eq!(tick(HIT_TYPES[0], 100), 2707);
+        assert_eq!(tick(HIT_TYPES[1], 100), 4358);
+        assert_eq!(tick(HIT_TYPES[3], 100), 5447);
+        assert_eq!(tick(HIT_TYPES[0], 95), 2571);
+        // Variance before the crit: floor(2571 * 1.61) and not floor(4358 * 0.95)
+        assert_eq!(tick(HIT_TYPES[1], 95), 4139);
+    }
+
+    #[test]
+    fn buffs_floor_one_by_one() {
+        let buffs = Buffs {
+            damage: vec![Unit(105), Unit(103)],
+            ..Default::default()
+        };
+        // floor(floor(12116 * 1.05) * 1.03)
+        assert_eq!(damage(&STATS, &DOSIS, HIT_TYPES[0], 100, &buffs), 13102);
+    }
+
+    fn average_over_variance(hit: HitType, buffs: &Buffs) -> f64 {
+        VARIANCE.map(|variance| damage(&STATS, &DOSIS, hit, variance, buffs) as f64).sum::<f64>() / 11.0
+    }
+
+    #[test]
+    fn expected_damage_weighs_hit_types() {
+        let buffs = Buffs::default();
+        // 26% crit and 23.1% direct hit from the stats
+        let expected = 0.74 * 0.769 * average_over_variance(HIT_TYPES[0], &buffs)
+            + 0.26 * 0.769 * average_over_variance(HIT_TYPES[1], &buffs)
+            + 0.74 * 0.231 * average_over_variance(HIT_TYPES[2], &buffs)
+            + 0.26 * 0.231 * average_over_variance(HIT_TYPES[3], &buffs);
+        assert!((expected_damage(&STATS, &DOSIS, &buffs) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn expected_damage_under_chain_stratagem() {
+        let buffs = rate_buffs(100, 0);
+        let expected = 0.64 * 0.769 * average_over_variance(HIT_TYPES[0], &buffs)
+            + 0.36 * 0.769 * average_over_variance(HIT_TYPES[1], &buffs)
+            + 0.64 * 0.231 * average_over_variance(HIT_TYPES[2], &buffs)
+            + 0.36 * 0.231 * average_over_variance(HIT_TYPES[3], &buffs);
+        assert!((expected_damage(&STATS, &DOSIS, &buffs) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn no_rate_buffs() {
+        assert_eq!(chances(&STATS, &DOSIS, &Buffs::default()), (0.26, 0.231));
+    }
+
+    #[test]
+    fn rate_buffs_add_percentage_points() {
+        // Crit and direct hit buffs in thousandths, and the chances they give from the 26% crit and
+        // 23.1% direct hit of the stats: +10% crit is 36%, not 10% more crit stat
+        let cases = [
+            ("Battle Litany", 100, 0, 0.36, 0.231),
+            ("Chain Stratagem and Battle Litany", 200, 0, 0.46, 0.231),
+            ("The Wanderer's Minuet", 20, 0, 0.28, 0.231),
+            ("Battle Voice", 0, 200, 0.26, 0.431),
+            ("Army's Paeon", 0, 30, 0.26, 0.261),
+        ];
+        for (name, critical, direct_hit, expected_critical, expected_direct_hit) in cases {
+            let (critical, direct_hit) = chances(&STATS, &DOSIS, &rate_buffs(critical, direct_hit));
+            assert!((critical - expected_critical).abs() < 1e-9, "{}: {} crit", name, critical);
+            assert!((direct_hit - expected_direct_hit).abs() < 1e-9, "{}: {} direct hit", name, direct_hit);
+        }
+    }
+
+    #[test]
+    fn rates_cap_at_one() {
+        assert_eq!(chances(&STATS, &DOSIS, &rate_buffs(900, 900)), (1.0, 1.0));
+    }
+
+    #[test]
+    fn rate_buffs_leave_hit_damage_alone() {
+        let buffed = damage(&STATS, &DOSIS, HIT_TYPES[3], 100, &rate_buffs(200, 200));
+        assert_eq!(buffed, 24382);
+    }
+
+    #[test]
+    fn guaranteed_crit_under_chain_stratagem() {
+        let action = Action { guaranteed: HitType { critical: true, direct_hit: false }, ..DOSIS };
+        assert_eq!(chances(&STATS, &action, &rate_buffs(100, 0)).0, 1.0);
+        // floor(19506 * (1 + 0.1 * 0.61))
+        assert_eq!(damage(&STATS, &action, HIT_TYPES[1], 100, &rate_buffs(100, 0)), 20695);
+        assert_eq!(damage(&STATS, &action, HIT_TYPES[1], 100, &Buffs::default()), 19506);
+    }
+
+    #[test]
+    fn guaranteed_direct_hit_under_battle_voice() {
+        let action = Action { guaranteed: HitType { critical: false, direct_hit: true }, ..DOSIS };
+        assert_eq!(chances(&STATS, &action, &rate_buffs(0, 200)).1, 1.0);
+        // floor(15145 * (1 + 0.2 * 0.25))
+        assert_eq!(damage(&STATS, &action, HIT_TYPES[2], 100, &rate_buffs(0, 200)), 15902);
+    }
+}
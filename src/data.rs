@@ -3,6 +3,8 @@ use crate::utils::Unit;
 pub type MatX = [u32; MeldType::Number as usize];
 pub type MatIX = [u32; MeldType::Number as usize];
 
+// Not all of those are used by the evaluators, but they are documented in the book
+#[allow(dead_code)]
 pub trait StatRepo {
     fn weapon_damage(&self) -> u32;
     fn mind(&self) -> u32;
@@ -77,6 +79,10 @@ pub trait StatRepo {
     fn dh_factor(&self) -> f64 {
         1.0 + self.dh_rate().scalar() * 0.25
     }
+
+    fn hp(&self) -> u32 {
+        3000 * 105 / 100 + self.vitality().saturating_sub(390) * 243 / 10
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -89,7 +95,7 @@ pub enum MeldType {
     Number,
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Hash)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Stats {
     pub weapon_damage: u32,
     pub mind: u32,
@@ -161,8 +167,73 @@ impl StatRepo for Stats {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stat {
+    WeaponDamage,
+    Mind,
+    Vitality,
+    Piety,
+    DirectHit,
+    Critical,
+    Determination,
+    SpellSpeed,
+    Hp,
+}
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Default)]
+pub const STATS: [Stat; 9] = [
+    Stat::WeaponDamage,
+    Stat::Mind,
+    Stat::Vitality,
+    Stat::Piety,
+    Stat::DirectHit,
+    Stat::Critical,
+    Stat::Determination,
+    Stat::SpellSpeed,
+    Stat::Hp,
+];
+
+impl Stat {
+    pub fn value(&self, stats: &impl StatRepo) -> u32 {
+        match self {
+            Stat::WeaponDamage => stats.weapon_damage(),
+            Stat::Mind => stats.mind(),
+            Stat::Vitality => stats.vitality(),
+            Stat::Piety => stats.piety(),
+            Stat::DirectHit => stats.direct_hit(),
+            Stat::Critical => stats.critical(),
+            Stat::Determination => stats.determination(),
+            Stat::SpellSpeed => stats.spell_speed(),
+            Stat::Hp => stats.hp(),
+        }
+    }
+
+    // The stat items actually carry, for the ones derived from another
+    pub fn item_stat(&self) -> Stat {
+        match self {
+            Stat::Hp => Stat::Vitality,
+            stat => *stat,
+        }
+    }
+}
+
+impl std::fmt::Display for Stat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stat::WeaponDamage => write!(f, "Weapon damage"),
+            Stat::Mind => write!(f, "Mind"),
+            Stat::Vitality => write!(f, "Vitality"),
+            Stat::Piety => write!(f, "Piety"),
+            Stat::DirectHit => write!(f, "Direct hit"),
+            Stat::Critical => write!(f, "Critical"),
+            Stat::Determination => write!(f, "Determination"),
+            Stat::SpellSpeed => write!(f, "Spell speed"),
+            Stat::Hp => write!(f, "HP"),
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Default)]
 pub enum ItemSlot {
     #[default]
     Weapon = 0,
@@ -207,6 +278,15 @@ impl Item {
     pub fn stat_max(&self) -> u32 {
         self.stats.stat_max()
     }
+
+    // Meld slots past the guaranteed ones, see `Gearset::meld_slots`
+    pub fn overmelds(&self) -> u32 {
+        if self.overmeldable == 0 {
+            0
+        } else {
+            5 - self.meld_slots
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, Default)]
@@ -216,6 +296,9 @@ pub struct Gearset {
     pub food: Item,
     pub meld_x: MatX,
     pub meld_ix: MatIX,
+    // Other item lists giving exactly the same stats, for display only: they are not compared or
+    // hashed
+    pub alternatives: Vec<[Item; 11]>,
 }
 
 impl Gearset {
@@ -277,20 +360,114 @@ impl Gearset {
         (slots_x, slots_ix)
     }
 
+    pub fn overmelds(&self) -> u32 {
+        self.items.iter().map(Item::overmelds).sum()
+    }
+
+    // Number of items to change to go from one gearset to the other, melds and food aside. Like
+    // `PartialEq`, the order of the rings doesn't matter.
+    pub fn item_swaps(&self, other: &Self) -> usize {
+        let slots = self.items[0..9].iter()
+            .zip(other.items[0..9].iter())
+            .filter(|(item, other_item)| item != other_item)
+            .count();
+        let rings = self.rings();
+        let other_rings = other.rings();
+        let kept_rings = if rings[0] == other_rings[0] || rings[0] == other_rings[1] {
+            let remaining = if rings[0] == other_rings[0] { &other_rings[1] } else { &other_rings[0] };
+            1 + usize::from(rings[1] == *remaining)
+        } else {
+            usize::from(rings[1] == other_rings[0] || rings[1] == other_rings[1])
+        };
+        slots + 2 - kept_rings
+    }
+
     pub fn is_valid(&self) -> bool {
         use ItemSlot::*;
         !(self.items[LeftRing as usize].name == self.items[RightRing as usize].name && self.items[LeftRing as usize].overmeldable == 0) || self.items[LeftRing as usize].name.is_empty()
     }
+
+    // The rings as a sorted pair, which ring goes on which hand doesn't matter. Everything but the
+    // slot is compared, like the other items.
+    fn rings(&self) -> [(&str, &Stats, u32, u32); 2] {
+        fn key(ring: &Item) -> (&str, &Stats, u32, u32) {
+            (ring.name.as_str(), &ring.stats, ring.meld_slots, ring.overmeldable)
+        }
+        let mut rings = [key(&self.items[9]), key(&self.items[10])];
+        rings.sort_unstable();
+        rings
+    }
+}
+
+// Consistent with `PartialEq`: the order of the rings doesn't matter
+impl std::hash::Hash for Gearset {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.items[0..9].hash(state);
+        self.rings().hash(state);
+        self.food.hash(state);
+        self.meld_x.hash(state);
+        self.meld_ix.hash(state);
+    }
 }
 
 impl std::cmp::PartialEq for Gearset {
     fn eq(&self, other: &Self) -> bool {
         self.items[0..9] == other.items[0..9]
-            && self.items[9..11].contains(&other.items[9])
-            && self.items[9..11].contains(&other.items[10])
+            && self.rings() == other.rings()
             && self.food == other.food
             && self.meld_x == other.meld_x
             && self.meld_ix == other.meld_ix
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn ring(slot: ItemSlot, name: &str) -> Item {
+        Item {
+            slot,
+            name: name.to_string(),
+            ..Item::default()
+        }
+    }
+
+    fn with_rings(left: &str, right: &str) -> Gearset {
+        let mut gearset = Gearset::default();
+        gearset.items[9] = ring(ItemSlot::LeftRing, left);
+        gearset.items[10] = ring(ItemSlot::RightRing, right);
+        gearset
+    }
+
+    fn hash(gearset: &Gearset) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        gearset.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn rings_in_any_order() {
+        let (a, b) = (with_rings("A", "B"), with_rings("B", "A"));
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+    }
+
+    #[test]
+    fn rings_compared_as_a_pair() {
+        let (a_b, a_a) = (with_rings("A", "B"), with_rings("A", "A"));
+        assert_ne!(a_b, a_a);
+        assert_ne!(a_a, a_b);
+        assert_eq!(with_rings("A", "B").item_swaps(&with_rings("B", "A")), 0);
+    }
+
+    #[test]
+    fn rings_compared_in_full() {
+        let mut other_stats = with_rings("A", "B");
+        other_stats.items[10].stats.critical = 100;
+        assert_ne!(with_rings("B", "A"), other_stats);
+        assert_eq!(with_rings("B", "A").item_swaps(&other_stats), 1);
+    }
+}
@@ -1,4 +1,5 @@
 mod solver;
+mod damage;
 mod utils;
 mod ui;
 mod data;
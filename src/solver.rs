@@ -1,18 +1,55 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
 
 use crate::data::*;
+use crate::ui::UiLink;
 
 pub mod infinite_dummy;
 pub mod timeline;
+pub mod timeline_cache;
+pub mod raid_buffs;
 pub mod split;
 pub mod rolling;
+pub mod pruning;
+pub mod pool;
+pub mod annealing;
+pub mod genetic;
+pub mod constraint;
+pub mod tiers;
+pub mod costs;
+pub mod pareto;
+pub mod equivalence;
+pub mod checkpoint;
+pub mod incremental;
+pub mod monte_carlo;
 
 pub use infinite_dummy::*;
 pub use timeline::*;
+pub use timeline_cache::*;
+pub use raid_buffs::*;
 pub use split::*;
 pub use rolling::*;
+pub use pruning::*;
+pub use pool::ItemPool;
+use pool::greedy_melds;
+pub use annealing::*;
+pub use genetic::*;
+pub use constraint::*;
+pub use tiers::*;
+pub use costs::*;
+pub use pareto::*;
+pub use equivalence::{EquivalenceClasses, SlotAlternatives};
+pub use checkpoint::*;
+pub use incremental::*;
+pub use monte_carlo::*;
 
-const SAGE_BASE: Stats = Stats {
+pub(crate) const SAGE_BASE: Stats = Stats {
     weapon_damage: 0,
     mind: 448,
     vitality: 390,
@@ -28,6 +65,14 @@ pub enum ItemSlotConversionError {
     Invalid(String)
 }
 
+impl std::fmt::Display for ItemSlotConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ItemSlotConversionError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
 impl std::fmt::Display for ItemSlot {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -72,16 +117,172 @@ impl std::str::FromStr for ItemSlot {
 }
 
 pub trait Solver {
-    fn solve(&self) -> eyre::Result<Vec<Gearset>>;
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution>;
     fn dps(&self, gearset: &Gearset) -> f64;
 }
 
+// Builds a solver for a given item list and set of constraints, for the solvers that run other
+// solvers several times
+pub type SolverBuilder = dyn Fn(Vec<Item>, Constraints) -> Arc<dyn Solver + Send+Sync> + Send+Sync;
+
+pub struct Solution {
+    pub gearsets: Vec<Gearset>,
+    // The run was stopped early, these are only the best gearsets found so far
+    pub partial: bool,
+    pub constraint_costs: Vec<ConstraintCost>,
+    pub item_impacts: Vec<ItemImpact>,
+}
+
+impl Solution {
+    // `partial` where the run actually stopped early: a run finishing after the time budget ran
+    // out still went through everything
+    pub fn new(gearsets: Vec<Gearset>, partial: bool) -> Self {
+        Self {
+            gearsets,
+            partial,
+            constraint_costs: Vec::new(),
+            item_impacts: Vec::new(),
+        }
+    }
+}
+
+// Number of ways `slots` materias can be spread over the meld types without going over what
+// `possible` allows for each type. This is the number of tentative melds the solvers enumerate,
+// without building them.
+pub(crate) fn meld_combinations(possible: &MatX, slots: u32) -> usize {
+    let slots = slots as usize;
+    let mut ways = vec![0; slots + 1];
+    ways[0] = 1;
+    for count in possible.iter().map(|count| *count as usize) {
+        let mut next = vec![0; slots + 1];
+        for (used, ways) in ways.iter().enumerate().filter(|(_, ways)| **ways != 0) {
+            for added in 0..=count.min(slots - used) {
+                next[used + added] += ways;
+            }
+        }
+        ways = next;
+    }
+    ways[slots]
+}
+
+// What a cancelled stage returns: the gearsets it ranked so far, or the previous stage's if it had
+// none yet. They are completed cheaply so that only whole gearsets come out, with greedy materias in
+// the free meld slots and the best food if they have none yet. Gearsets that still break the
+// constraints are dropped, the rest are sorted best first again.
+pub(crate) fn complete_cancelled(
+    gearsets: Vec<Gearset>,
+    previous: Vec<Gearset>,
+    food: &[Item],
+    evaluator: &dyn Evaluator,
+    constraints: &Constraints,
+) -> Vec<Gearset> {
+    let gearsets = if gearsets.is_empty() { previous } else { gearsets };
+    let mut completed: Vec<_> = gearsets.into_iter()
+        .filter_map(|mut gearset| {
+            greedy_melds(&mut gearset, evaluator);
+            if gearset.food.name.is_empty() {
+                gearset = food.iter()
+                    .map(|food| Gearset {
+                        food: food.clone(),
+                        ..gearset.clone()
+                    })
+                    .filter(|fed| constraints.accepts(fed))
+                    .map(|fed| (evaluator.dps(&fed), fed))
+                    .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())?
+                    .1;
+            }
+            constraints.accepts(&gearset).then(|| (evaluator.dps(&gearset), gearset))
+        })
+        .collect();
+    completed.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap());
+    completed.into_iter().map(|(_, gearset)| gearset).collect()
+}
+
+// Shared between the UI and the solver thread. Solvers check it between candidates and stop
+// as soon as the run is cancelled or the time budget is exhausted.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_budget(self, budget: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + budget),
+            ..self
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return true;
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            // Latch it so we don't query the clock anymore
+            self.cancel();
+            return true;
+        }
+        false
+    }
+}
+
+// Tells whether an enumeration stage was cut short by the token, rather than running out of
+// candidates: `.take_while(|_| interruption.proceed())`
+pub(crate) struct Interruption<'a> {
+    token: &'a CancellationToken,
+    happened: Cell<bool>,
+}
+
+impl<'a> Interruption<'a> {
+    pub fn new(token: &'a CancellationToken) -> Self {
+        Self {
+            token,
+            happened: Cell::new(false),
+        }
+    }
+
+    pub fn proceed(&self) -> bool {
+        if self.token.is_cancelled() {
+            self.happened.set(true);
+        }
+        !self.happened.get()
+    }
+
+    pub fn happened(&self) -> bool {
+        self.happened.get()
+    }
+}
+
 pub trait Evaluator {
-    fn dps(&self, gearset: &Gearset) -> f64;
+    // DPS of a stat line, whichever gear, melds and food it comes from
+    fn stats_dps(&self, stats: &Stats) -> f64;
+
+    // Which evaluator this is and everything its DPS depends on besides the stats, so that
+    // results of different settings can be told apart
+    fn settings(&self) -> String;
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.stats_dps(&gearset.stats())
+    }
+
+    // DPS of every gearset, in order
+    fn batch_dps(&self, gearsets: &[Gearset]) -> Vec<f64> {
+        gearsets.iter().map(|gearset| self.dps(gearset)).collect()
+    }
 }
 
+// The DPS is computed once when the gearset is wrapped, not on every comparison
 pub struct EvaluatorWrapper {
-    evaluator: Arc<dyn Evaluator>,
+    dps: f64,
     gearset: Gearset,
 }
 
@@ -95,24 +296,274 @@ impl Eq for EvaluatorWrapper {}
 
 impl PartialOrd for EvaluatorWrapper {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.evaluator.dps(&self.gearset).partial_cmp(&other.evaluator.dps(&other.gearset))
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for EvaluatorWrapper {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.dps.partial_cmp(&other.dps).unwrap()
     }
 }
 
-#[derive(PartialEq, Eq)]
+// How many of the current best gearsets are sent to the UI while a solver is running, and how often
+const PUBLISHED_GEARSETS: usize = 100;
+pub(crate) const PUBLISH_INTERVAL: Duration = Duration::from_millis(500);
+// Candidates are scored by batches of this many, see `Evaluator::batch_dps`
+const EVALUATION_BATCH: usize = 32;
+
+// Keeps the K best gearsets seen so far. Unlike `k_smallest`, the current best can be looked at
+// while candidates are still coming in, which is what makes live results possible.
+pub struct TopK<'a> {
+    k: usize,
+    evaluator: Arc<dyn Evaluator + Send + Sync>,
+    heap: BinaryHeap<Reverse<EvaluatorWrapper>>,
+    // Candidates not scored yet
+    pending: Vec<Gearset>,
+    min_item_swaps: usize,
+    ui_link: Option<&'a UiLink>,
+    // Checkpointer, stage and input of the stage
+    checkpoint: Option<(&'a Checkpointer, usize, &'a [Gearset])>,
+    last_publish: Instant,
+}
+
+impl<'a> TopK<'a> {
+    pub fn new(k: usize, evaluator: Arc<dyn Evaluator + Send + Sync>) -> Self {
+        Self {
+            k,
+            evaluator,
+            heap: BinaryHeap::with_capacity(k + 1),
+            pending: Vec::with_capacity(EVALUATION_BATCH),
+            min_item_swaps: 0,
+            ui_link: None,
+            checkpoint: None,
+            last_publish: Instant::now(),
+        }
+    }
+
+    // Periodically send the current best gearsets to the UI as provisional results. Only for the
+    // stages producing complete gearsets, the others would show gear without melds or food.
+    pub fn publish_to(self, ui_link: &'a UiLink) -> Self {
+        Self {
+            ui_link: Some(ui_link),
+            ..self
+        }
+    }
+
+    // Periodically save the current best gearsets as the progress of `stage`
+    pub fn checkpoint_to(self, checkpointer: &'a Checkpointer, stage: usize, input: &'a [Gearset]) -> Self {
+        Self {
+            checkpoint: Some((checkpointer, stage, input)),
+            ..self
+        }
+    }
+
+    // Keep gearsets at least `min_item_swaps` item swaps apart, the best of the ones closer than
+    // that wins. With 1, gearsets differing only by their melds or food are merged.
+    pub fn diverse(self, min_item_swaps: usize) -> Self {
+        Self {
+            min_item_swaps,
+            ..self
+        }
+    }
+
+    pub fn push(&mut self, gearset: Gearset) {
+        self.pending.push(gearset);
+        if self.pending.len() >= EVALUATION_BATCH {
+            self.flush();
+        }
+    }
+
+    // Scores the pending candidates and keeps the best ones. Only done with every pending
+    // candidate in the heap, checkpoints would otherwise lose the ones still pending.
+    fn flush(&mut self) {
+        let dps = self.evaluator.batch_dps(&self.pending);
+        let mut pending = std::mem::take(&mut self.pending);
+        for (gearset, dps) in pending.drain(..).zip(dps) {
+            self.insert(gearset, dps);
+        }
+        self.pending = pending;
+
+        if self.last_publish.elapsed() >= PUBLISH_INTERVAL {
+            self.publish();
+        }
+        if let Some((checkpointer, stage, input)) = self.checkpoint.filter(|(checkpointer, _, _)| checkpointer.is_due()) {
+            let best: Vec<_> = self.heap.iter()
+                .map(|Reverse(wrapper)| wrapper.gearset.clone())
+                .collect();
+            checkpointer.save(stage, input, &best);
+        }
+    }
+
+    fn insert(&mut self, gearset: Gearset, dps: f64) {
+        // Would be popped right away, most candidates are
+        if self.heap.len() >= self.k && self.heap.peek().is_some_and(|Reverse(worst)| dps <= worst.dps) {
+            return;
+        }
+        let wrapper = EvaluatorWrapper { dps, gearset };
+        if self.min_item_swaps > 0 {
+            let min_item_swaps = self.min_item_swaps;
+            let too_close = |other: &EvaluatorWrapper| other.gearset.item_swaps(&wrapper.gearset) < min_item_swaps;
+            if self.heap.iter().any(|Reverse(other)| too_close(other) && other.dps >= wrapper.dps) {
+                return;
+            }
+            self.heap.retain(|Reverse(other)| !too_close(other));
+        }
+        self.heap.push(Reverse(wrapper));
+        if self.heap.len() > self.k {
+            self.heap.pop();
+        }
+    }
+
+    fn publish(&mut self) {
+        self.last_publish = Instant::now();
+        if let Some(ui_link) = self.ui_link {
+            // Smallest `Reverse` are the best gearsets
+            let gearsets = self.heap.iter()
+                .k_smallest(PUBLISHED_GEARSETS)
+                .map(|Reverse(wrapper)| (wrapper.gearset.clone(), wrapper.dps))
+                .collect();
+            if let Err(error) = ui_link.provisional_gearsets(gearsets) {
+                tracing::warn!("Could not publish provisional gearsets: {}", error);
+            }
+        }
+    }
+
+    // Best gearset first
+    pub fn into_sorted_vec(mut self) -> Vec<Gearset> {
+        if !self.pending.is_empty() {
+            self.flush();
+        }
+        self.heap.into_sorted_vec().into_iter()
+            .map(|Reverse(EvaluatorWrapper { gearset, .. })| gearset)
+            .collect()
+    }
+}
+
+pub trait TopKExt: Iterator<Item = Gearset> + Sized {
+    fn top_k(self, mut top_k: TopK) -> Vec<Gearset> {
+        self.for_each(|gearset| top_k.push(gearset));
+        top_k.into_sorted_vec()
+    }
+}
+
+impl<T: Iterator<Item = Gearset>> TopKExt for T {}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiversityConfig {
+    // 0 keeps every gearset, 1 merges the ones with the same items
+    pub min_item_swaps: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum SolverType {
     Split,
     Rolling,
+    Annealing,
+    Genetic,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum EvaluatorType {
     InfiniteDummy,
     Timeline,
+    MonteCarlo,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_items() -> Vec<Item> {
+        let gear = ITEM_SLOTS.into_iter()
+            .filter(|slot| *slot != ItemSlot::RightRing)
+            .flat_map(|slot| (0..2).map(move |index| Item {
+                slot,
+                name: format!("{} {}", slot, index),
+                stats: Stats {
+                    weapon_damage: if slot == ItemSlot::Weapon { 120 } else { 0 },
+                    mind: 200,
+                    vitality: 200,
+                    critical: 100 + 20 * index,
+                    determination: 120 - 20 * index,
+                    ..Stats::default()
+                },
+                meld_slots: 2,
+                overmeldable: 1,
+            }));
+        let food = [Stats { critical: 50, ..Stats::default() }, Stats { determination: 50, ..Stats::default() }]
+            .into_iter()
+            .enumerate()
+            .map(|(index, stats)| Item {
+                slot: ItemSlot::Food,
+                name: format!("Food {}", index),
+                stats,
+                ..Item::default()
+            });
+        gear.chain(food).collect()
+    }
+
+    // Cancels the run as soon as it scores a gearset `trigger` matches
+    struct CancelOn {
+        token: CancellationToken,
+        trigger: fn(&Gearset) -> bool,
+    }
+
+    impl Evaluator for CancelOn {
+        fn stats_dps(&self, stats: &Stats) -> f64 {
+            InfiniteDummyEvaluator::default().stats_dps(stats)
+        }
+
+        fn dps(&self, gearset: &Gearset) -> f64 {
+            if (self.trigger)(gearset) {
+                self.token.cancel();
+            }
+            self.stats_dps(&gearset.stats())
+        }
+
+        fn settings(&self) -> String {
+            "Cancel on".to_string()
+        }
+    }
+
+    fn assert_complete(solution: &Solution) {
+        assert!(solution.partial);
+        assert!(!solution.gearsets.is_empty());
+        for gearset in &solution.gearsets {
+            assert!(gearset.items.iter().all(|item| !item.name.is_empty()));
+            let (slots_x, slots_ix) = gearset.meld_slots();
+            assert_eq!(gearset.meld_x.iter().sum::<u32>(), slots_x);
+            assert_eq!(gearset.meld_ix.iter().sum::<u32>(), slots_ix);
+            assert!(!gearset.food.name.is_empty());
+        }
+    }
+
+    #[test]
+    fn cancelled_meld_stages_return_complete_gearsets() {
+        let triggers: [fn(&Gearset) -> bool; 2] = [
+            |gearset| gearset.meld_x.iter().sum::<u32>() > 0,
+            |gearset| gearset.meld_ix.iter().sum::<u32>() > 0,
+        ];
+        for trigger in triggers {
+            let token = CancellationToken::new();
+            let evaluator = Arc::new(CancelOn { token: token.clone(), trigger });
+            let solution = RollingSolver::new(UiLink::new(), evaluator.clone())
+                .with_items(test_items())
+                .with_config(RollingConfig {
+                    rolling_k: 8,
+                    ..RollingConfig::default()
+                })
+                .solve(&token)
+                .unwrap();
+            assert_complete(&solution);
+
+            let token = CancellationToken::new();
+            let evaluator = Arc::new(CancelOn { token: token.clone(), trigger });
+            let solution = SplitSolver::new(UiLink::new(), evaluator)
+                .with_items(test_items())
+                .solve(&token)
+                .unwrap();
+            assert_complete(&solution);
+        }
+    }
 }
@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use crate::data::*;
+use crate::ui::UiLink;
+use crate::solver::{CancellationToken, Constraints, DiversityConfig, Evaluator, ItemPool, Solution, Solver, TopK};
+use crate::solver::pool::{repair_melds, swap_meld};
+
+#[derive(Clone)]
+pub struct AnnealingConfig {
+    pub seed: u64,
+    pub iterations: usize,
+    pub restarts: usize,
+    // In DPS: a move losing that much DPS is accepted ~37% of the time at the start of a restart
+    pub initial_temperature: f64,
+    pub k: usize,
+}
+
+impl Default for AnnealingConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            iterations: 20000,
+            restarts: 4,
+            initial_temperature: 20.0,
+            k: 10,
+        }
+    }
+}
+
+// The temperature goes down geometrically to initial_temperature / TEMPERATURE_RATIO
+const TEMPERATURE_RATIO: f64 = 1000.0;
+
+// Simulated annealing over item, meld and food swaps.
+//
+// The first restart begins from a greedy gearset, the next ones from a random one. Every accepted
+// gearset is a candidate for the results, so the results get better the longer it runs and
+// cancelling it always leaves something usable. The walk itself ignores the constraints, only the
+// candidates have to satisfy them.
+pub struct AnnealingSolver {
+    items: Vec<Item>,
+    ui_link: UiLink,
+    evaluator: Arc<dyn Evaluator + Send+Sync>,
+    config: AnnealingConfig,
+    constraints: Constraints,
+    diversity: DiversityConfig,
+}
+
+impl AnnealingSolver {
+    pub fn new(ui_link: UiLink, evaluator: Arc<dyn Evaluator + Send+Sync>) -> Self {
+        Self {
+            items: Vec::default(),
+            ui_link,
+            evaluator,
+            config: AnnealingConfig::default(),
+            constraints: Constraints::default(),
+            diversity: DiversityConfig::default(),
+        }
+    }
+
+    pub fn with_items(self, items: Vec<Item>) -> Self {
+        Self {
+            items,
+            ..self
+        }
+    }
+
+    pub fn with_config(self, config: AnnealingConfig) -> Self {
+        Self {
+            config,
+            ..self
+        }
+    }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    pub fn with_diversity(self, diversity: DiversityConfig) -> Self {
+        Self {
+            diversity,
+            ..self
+        }
+    }
+
+    fn neighbour(&self, pool: &ItemPool, gearset: &Gearset, rng: &mut StdRng) -> Option<Gearset> {
+        let mut neighbour = gearset.clone();
+        match rng.gen_range(0..20) {
+            // Item swap
+            0..=7 => {
+                let slot = *ITEM_SLOTS.choose(rng).unwrap();
+                neighbour.items[slot as usize] = pool.items(slot).choose(rng).unwrap().clone();
+                if !neighbour.is_valid() {
+                    return None;
+                }
+                repair_melds(&mut neighbour, rng);
+            },
+            // Meld swap
+            8..=16 => {
+                let (possible_x, possible_ix) = neighbour.possible_melds();
+                let swapped = if rng.gen_bool(0.5) {
+                    swap_meld(&mut neighbour.meld_x, &possible_x, rng)
+                } else {
+                    swap_meld(&mut neighbour.meld_ix, &possible_ix, rng)
+                };
+                if !swapped {
+                    return None;
+                }
+            },
+            // Food swap
+            _ => {
+                neighbour.food = pool.food().choose(rng).unwrap().clone();
+            },
+        }
+        (neighbour != *gearset).then_some(neighbour)
+    }
+}
+
+impl Solver for AnnealingSolver {
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        self.ui_link.stage("Loading items...", 0)?;
+        let pool = ItemPool::new(&self.items);
+        if let Err(error) = pool.check() {
+            self.ui_link.message(format!("ERROR: {}", error))?;
+            return Err(error);
+        }
+
+        let mut rng = StdRng::seed_from_u64(self.config.seed);
+        let mut best = TopK::new(self.config.k, self.evaluator.clone())
+            .diverse(self.diversity.min_item_swaps)
+            .publish_to(&self.ui_link);
+        let mut seen = HashSet::new();
+
+        let mut partial = false;
+        for restart in 0..self.config.restarts {
+            self.ui_link.stage(format!("Annealing (restart {}/{})...", restart + 1, self.config.restarts), self.config.iterations)?;
+            let progress = self.ui_link.progress();
+
+            let mut current = if restart == 0 {
+                pool.greedy_gearset(self.evaluator.as_ref())
+            } else {
+                pool.random_gearset(&mut rng)
+            };
+            let mut current_dps = self.evaluator.dps(&current);
+            if seen.insert(current.clone()) && self.constraints.accepts(&current) {
+                best.push(current.clone());
+            }
+
+            for iteration in 0..self.config.iterations {
+                if token.is_cancelled() {
+                    partial = true;
+                    break;
+                }
+                progress.tick();
+
+                let progression = iteration as f64 / self.config.iterations as f64;
+                let temperature = self.config.initial_temperature * TEMPERATURE_RATIO.powf(-progression);
+
+                let neighbour = match self.neighbour(&pool, &current, &mut rng) {
+                    Some(neighbour) => neighbour,
+                    None => continue,
+                };
+                let neighbour_dps = self.evaluator.dps(&neighbour);
+                let delta = neighbour_dps - current_dps;
+                if delta >= 0.0 || rng.gen::<f64>() < (delta / temperature).exp() {
+                    if seen.insert(neighbour.clone()) && self.constraints.accepts(&neighbour) {
+                        best.push(neighbour.clone());
+                    }
+                    current = neighbour;
+                    current_dps = neighbour_dps;
+                }
+            }
+
+            if partial {
+                break;
+            }
+        }
+
+        Ok(Solution::new(best.into_sorted_vec(), partial))
+    }
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.evaluator.dps(gearset)
+    }
+}
@@ -0,0 +1,231 @@
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+
+use crate::data::*;
+use crate::solver::{Evaluator, SAGE_BASE};
+
+#[derive(Debug, Clone)]
+pub struct CheckpointConfig {
+    pub enabled: bool,
+    pub path: String,
+    pub interval_secs: u64,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "checkpoint.csv".to_string(),
+            interval_secs: 60,
+        }
+    }
+}
+
+// Where a run was: the stage, what the stage enumerates candidates from, how many candidates it
+// went through and the best ones so far. Alternatives are not saved, they are for display only.
+struct Checkpoint {
+    stage: usize,
+    cursor: usize,
+    input: Vec<Gearset>,
+    best: Vec<Gearset>,
+}
+
+// Saves the progress of an enumeration solver to disk every now and then, and picks a run back up
+// from a checkpoint with the same fingerprint. The fingerprint covers the items, the solver
+// settings and the evaluator with its own settings, as candidates before the cursor are never
+// looked at again.
+//
+// Stages are numbered by the solver, the cursor is counted by `advance` for every candidate taken
+// out of the enumeration, before any filtering.
+pub struct Checkpointer {
+    config: CheckpointConfig,
+    fingerprint: u64,
+    loaded: RefCell<Option<Checkpoint>>,
+    cursor: Cell<usize>,
+    last_save: Cell<Instant>,
+}
+
+// FNV-1a, unlike `DefaultHasher` it gives the same hash on every Rust release, so a checkpoint
+// stays valid across toolchain updates
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl Checkpointer {
+    pub fn new(config: CheckpointConfig, items: &[Item], evaluator: &dyn Evaluator, settings: impl std::fmt::Debug) -> Self {
+        let fingerprint = fnv1a(format!("{:?}\n{}\n{:?}", items, evaluator.settings(), settings).as_bytes());
+
+        let loaded = if config.enabled && std::path::Path::new(&config.path).exists() {
+            match load(&config.path, fingerprint, items) {
+                Ok(Some(checkpoint)) => {
+                    tracing::info!("Resuming from {}: stage {}, {} candidates done", config.path, checkpoint.stage, checkpoint.cursor);
+                    Some(checkpoint)
+                },
+                Ok(None) => {
+                    tracing::info!("{} is for other items or settings, starting over", config.path);
+                    None
+                },
+                Err(error) => {
+                    tracing::warn!("Could not load {}: {}", config.path, error);
+                    None
+                },
+            }
+        } else {
+            None
+        };
+
+        Self {
+            config,
+            fingerprint,
+            loaded: RefCell::new(loaded),
+            cursor: Cell::new(0),
+            last_save: Cell::new(Instant::now()),
+        }
+    }
+
+    // Stage to start from and its input, if resuming
+    pub fn start(&self) -> Option<(usize, Vec<Gearset>)> {
+        self.loaded.borrow().as_ref().map(|checkpoint| (checkpoint.stage, checkpoint.input.clone()))
+    }
+
+    // Candidates of the stage to skip and the best gearsets found before them, nothing unless
+    // resuming this very stage
+    pub fn begin_stage(&self, stage: usize) -> (usize, Vec<Gearset>) {
+        let mut loaded = self.loaded.borrow_mut();
+        let (cursor, best) = match loaded.take() {
+            Some(checkpoint) if checkpoint.stage == stage => (checkpoint.cursor, checkpoint.best),
+            other => {
+                *loaded = other;
+                (0, Vec::new())
+            },
+        };
+        self.cursor.set(cursor);
+        (cursor, best)
+    }
+
+    pub fn advance(&self) {
+        self.cursor.set(self.cursor.get() + 1);
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.config.enabled && self.last_save.get().elapsed() >= Duration::from_secs(self.config.interval_secs)
+    }
+
+    // Failing to save doesn't stop the run
+    pub fn save(&self, stage: usize, input: &[Gearset], best: &[Gearset]) {
+        if !self.config.enabled {
+            return;
+        }
+        self.last_save.set(Instant::now());
+        match save(&self.config.path, self.fingerprint, stage, self.cursor.get(), input, best) {
+            Ok(()) => tracing::info!("Saved checkpoint: stage {}, {} candidates done", stage, self.cursor.get()),
+            Err(error) => tracing::warn!("Could not save {}: {}", self.config.path, error),
+        }
+    }
+
+    // The run went through, there is nothing left to resume
+    pub fn finish(&self) {
+        if self.config.enabled && std::path::Path::new(&self.config.path).exists() {
+            if let Err(error) = std::fs::remove_file(&self.config.path) {
+                tracing::warn!("Could not remove {}: {}", self.config.path, error);
+            }
+        }
+    }
+}
+
+// One record per line, `;`-separated like the items: the fingerprint, the stage and cursor, then
+// one record per gearset with its item names, food name and melds
+fn save(path: &str, fingerprint: u64, stage: usize, cursor: usize, input: &[Gearset], best: &[Gearset]) -> eyre::Result<()> {
+    // Written next to the checkpoint first, so that a crash while saving keeps the previous one
+    let temporary = format!("{}.tmp", path);
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b';')
+        .flexible(true)
+        .from_path(&temporary)?;
+    writer.write_record(["fingerprint".to_string(), fingerprint.to_string()])?;
+    writer.write_record(["stage".to_string(), stage.to_string(), cursor.to_string()])?;
+    let gearsets = std::iter::repeat("input").zip(input)
+        .chain(std::iter::repeat("best").zip(best));
+    for (kind, gearset) in gearsets {
+        let record = std::iter::once(kind.to_string())
+            .chain(gearset.items.iter().map(|item| item.name.clone()))
+            .chain(std::iter::once(gearset.food.name.clone()))
+            .chain(gearset.meld_x.iter().chain(gearset.meld_ix.iter()).map(u32::to_string));
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+// `None` if the checkpoint is for other items or settings
+fn load(path: &str, fingerprint: u64, items: &[Item]) -> eyre::Result<Option<Checkpoint>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+    let mut records = reader.records();
+    let mut next = || records.next().ok_or_else(|| eyre::eyre!("Truncated checkpoint"));
+
+    let record = next()??;
+    if record.get(1) != Some(fingerprint.to_string().as_str()) {
+        return Ok(None);
+    }
+    let record = next()??;
+    let number = |index| record.get(index).unwrap_or_default().parse::<usize>();
+    let mut checkpoint = Checkpoint {
+        stage: number(1)?,
+        cursor: number(2)?,
+        input: Vec::new(),
+        best: Vec::new(),
+    };
+
+    for record in records {
+        let record = record?;
+        let gearset = load_gearset(&record, items)?;
+        match record.get(0) {
+            Some("input") => checkpoint.input.push(gearset),
+            Some("best") => checkpoint.best.push(gearset),
+            other => return Err(eyre::eyre!("Unexpected record: {:?}", other)),
+        }
+    }
+    Ok(Some(checkpoint))
+}
+
+fn load_gearset(record: &csv::StringRecord, items: &[Item]) -> eyre::Result<Gearset> {
+    let find = |slot: ItemSlot, index: usize| -> eyre::Result<Item> {
+        let name = record.get(index).ok_or_else(|| eyre::eyre!("Truncated gearset"))?;
+        if name.is_empty() {
+            return Ok(Item::default());
+        }
+        // Both ring slots are filled from the ring list
+        let list_slot = if slot == ItemSlot::RightRing { ItemSlot::LeftRing } else { slot };
+        let mut item = items.iter()
+            .find(|item| item.slot == list_slot && item.name == name)
+            .cloned()
+            .ok_or_else(|| eyre::eyre!("Unknown item: {}", name))?;
+        item.slot = slot;
+        Ok(item)
+    };
+    let melds = |start: usize| -> eyre::Result<MatX> {
+        let mut melds = MatX::default();
+        for (index, meld) in melds.iter_mut().enumerate() {
+            *meld = record.get(start + index).unwrap_or_default().parse()?;
+        }
+        Ok(melds)
+    };
+
+    let mut gearset = Gearset {
+        base: SAGE_BASE,
+        ..Default::default()
+    };
+    for (index, slot) in ITEM_SLOTS.iter().enumerate() {
+        gearset.items[index] = find(*slot, index + 1)?;
+    }
+    gearset.food = find(ItemSlot::Food, ITEM_SLOTS.len() + 1)?;
+    gearset.meld_x = melds(ITEM_SLOTS.len() + 2)?;
+    gearset.meld_ix = melds(ITEM_SLOTS.len() + 2 + gearset.meld_x.len())?;
+    Ok(gearset)
+}
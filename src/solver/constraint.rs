@@ -0,0 +1,273 @@
+use std::collections::BTreeSet;
+
+use crate::data::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcdConstraint {
+    Equal,
+    AtMost,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatBound {
+    pub stat: Stat,
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+}
+
+impl StatBound {
+    pub fn new(stat: Stat) -> Self {
+        Self {
+            stat,
+            min: None,
+            max: None,
+        }
+    }
+
+    fn accepts(&self, stats: &Stats) -> bool {
+        let value = self.stat.value(stats);
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
+impl std::fmt::Display for StatBound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => write!(f, "{} ≤ {} ≤ {}", min, self.stat, max),
+            (Some(min), None) => write!(f, "{} ≥ {}", self.stat, min),
+            (None, Some(max)) => write!(f, "{} ≤ {}", self.stat, max),
+            (None, None) => write!(f, "{}", self.stat),
+        }
+    }
+}
+
+// Hard requirements on the gearsets, on top of maximizing DPS
+#[derive(Debug, Clone)]
+pub struct Constraints {
+    pub gcd: Option<GcdConstraint>,
+    // In hundredths of seconds, same unit as `StatRepo::gcd`
+    pub gcd_target: u32,
+    // Solve once for every GCD tier the items can reach and keep the best gearset of each
+    pub per_gcd_tier: bool,
+    pub stat_bounds: Vec<StatBound>,
+    // Overmelded materias, see `Gearset::overmelds`
+    pub max_overmelds: Option<u32>,
+    // Gear (not food) missing from `owned_items`
+    pub max_unowned_items: Option<u32>,
+    // Sorted, so that it always prints the same for checkpoint fingerprints
+    pub owned_items: BTreeSet<String>,
+}
+
+impl Default for Constraints {
+    fn default() -> Self {
+        Self {
+            gcd: None,
+            gcd_target: 250,
+            per_gcd_tier: false,
+            stat_bounds: Vec::new(),
+            max_overmelds: None,
+            max_unowned_items: None,
+            owned_items: BTreeSet::new(),
+        }
+    }
+}
+
+impl Constraints {
+    // The GCD pinned to a single tier, for the per-tier mode
+    pub fn with_gcd_tier(self, gcd: u32) -> Self {
+        Self {
+            gcd: Some(GcdConstraint::Equal),
+            gcd_target: gcd,
+            per_gcd_tier: false,
+            ..self
+        }
+    }
+
+    fn active_stat_bounds(&self) -> impl Iterator<Item = &StatBound> {
+        self.stat_bounds.iter().filter(|bound| bound.min.is_some() || bound.max.is_some())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.gcd.is_some()
+            || self.active_stat_bounds().next().is_some()
+            || self.max_overmelds.is_some()
+            || self.max_unowned_items.is_some()
+    }
+
+    pub fn unowned_items(&self, gearset: &Gearset) -> u32 {
+        gearset.items.iter()
+            .filter(|item| !item.name.is_empty() && !self.owned_items.contains(&item.name))
+            .count() as u32
+    }
+
+    // Every active constraint on its own, to measure what each of them costs
+    pub fn split(&self) -> Vec<(String, Constraints)> {
+        let gcd = self.gcd.map(|gcd| {
+            let label = match gcd {
+                GcdConstraint::Equal => format!("GCD = {:.2}", self.gcd_target as f64 / 100.0),
+                GcdConstraint::AtMost => format!("GCD ≤ {:.2}", self.gcd_target as f64 / 100.0),
+            };
+            (label, Constraints {
+                gcd: Some(gcd),
+                gcd_target: self.gcd_target,
+                ..Default::default()
+            })
+        });
+        let stat_bounds = self.active_stat_bounds().map(|bound| {
+            (bound.to_string(), Constraints {
+                stat_bounds: vec![bound.clone()],
+                ..Default::default()
+            })
+        });
+        let max_overmelds = self.max_overmelds.map(|max_overmelds| {
+            (format!("Overmelds ≤ {}", max_overmelds), Constraints {
+                max_overmelds: Some(max_overmelds),
+                ..Default::default()
+            })
+        });
+        let max_unowned_items = self.max_unowned_items.map(|max_unowned_items| {
+            (format!("Unowned items ≤ {}", max_unowned_items), Constraints {
+                max_unowned_items: Some(max_unowned_items),
+                owned_items: self.owned_items.clone(),
+                ..Default::default()
+            })
+        });
+        gcd.into_iter()
+            .chain(stat_bounds)
+            .chain(max_overmelds)
+            .chain(max_unowned_items)
+            .collect()
+    }
+
+    // Items with more of this stat are not always better
+    pub fn caps(&self, stat: Stat) -> bool {
+        let gcd_caps = stat == Stat::SpellSpeed && (self.per_gcd_tier || self.gcd == Some(GcdConstraint::Equal));
+        gcd_caps || self.active_stat_bounds().any(|bound| bound.stat.item_stat() == stat && bound.max.is_some())
+    }
+
+    // Items with less of this stat are not always worse, even if it doesn't add DPS
+    pub fn needs(&self, stat: Stat) -> bool {
+        self.active_stat_bounds().any(|bound| bound.stat.item_stat() == stat && bound.min.is_some())
+    }
+
+    // The gearset is complete and satisfies every constraint
+    pub fn accepts(&self, gearset: &Gearset) -> bool {
+        let stats = gearset.stats();
+        let gcd = stats.gcd().0;
+        let gcd_accepted = match self.gcd {
+            None => true,
+            Some(GcdConstraint::Equal) => gcd == self.gcd_target,
+            Some(GcdConstraint::AtMost) => gcd <= self.gcd_target,
+        };
+        gcd_accepted
+            && self.active_stat_bounds().all(|bound| bound.accepts(&stats))
+            && self.items_reachable(gearset)
+    }
+
+    // Overmelds and unowned items only depend on the items, and only go up as items are added
+    fn items_reachable(&self, gearset: &Gearset) -> bool {
+        self.max_overmelds.is_none_or(|max| gearset.overmelds() <= max)
+            && self.max_unowned_items.is_none_or(|max| self.unowned_items(gearset) <= max)
+    }
+
+    // The gearset is being built, and the items, melds or food still missing could make it
+    // satisfy every constraint. Those only ever add stats, so what the gearset has now is a lower
+    // bound and the most the missing parts could add is an upper bound.
+    //
+    // `food` is what the food can be picked from, if the gearset has none yet.
+    pub fn reachable(&self, gearset: &Gearset, food: &[Item]) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+
+        let stats = gearset.stats();
+        let max_stats = max_stats(gearset, &stats, food);
+
+        let slowest = stats.gcd().0;
+        let fastest = max_stats.as_ref().map_or(0, |max_stats| max_stats.gcd().0);
+        let gcd_reachable = match self.gcd {
+            None => true,
+            Some(GcdConstraint::Equal) => fastest <= self.gcd_target && self.gcd_target <= slowest,
+            Some(GcdConstraint::AtMost) => fastest <= self.gcd_target,
+        };
+
+        gcd_reachable && self.items_reachable(gearset) && self.active_stat_bounds().all(|bound| {
+            // Empty slots can bring any amount of anything
+            let min_reachable = bound.min.is_none_or(|min| {
+                max_stats.as_ref().is_none_or(|max_stats| bound.stat.value(max_stats) >= min)
+            });
+            let max_reachable = bound.max.is_none_or(|max| bound.stat.value(&stats) <= max);
+            min_reachable && max_reachable
+        })
+    }
+}
+
+// Most stats the gearset can end up with once its melds and food are picked, or `None` if some
+// items are still missing
+fn max_stats(gearset: &Gearset, stats: &Stats, food: &[Item]) -> Option<Stats> {
+    if gearset.items.iter().any(|item| item.name.is_empty()) {
+        return None;
+    }
+
+    let mut max_stats = stats.clone();
+    let (possible_x, possible_ix) = gearset.possible_melds();
+    let melds = [
+        (MeldType::Critical, &mut max_stats.critical),
+        (MeldType::Determination, &mut max_stats.determination),
+        (MeldType::DirectHit, &mut max_stats.direct_hit),
+        (MeldType::SpellSpeed, &mut max_stats.spell_speed),
+    ];
+    for (meld, stat) in melds {
+        // Melds are picked all at once for a grade, so an empty grade has not been picked yet
+        if gearset.meld_x.iter().sum::<u32>() == 0 {
+            *stat += possible_x[meld as usize] * 36;
+        }
+        if gearset.meld_ix.iter().sum::<u32>() == 0 {
+            *stat += possible_ix[meld as usize] * 12;
+        }
+    }
+    // Food gives at most 10%, see `Stats::apply_food`
+    if gearset.food.name.is_empty() {
+        let bonus = |stat: Stat, value: u32| food.iter()
+            .map(|food| stat.value(&food.stats).min(value / 10))
+            .max()
+            .unwrap_or_default();
+        max_stats.critical += bonus(Stat::Critical, max_stats.critical);
+        max_stats.direct_hit += bonus(Stat::DirectHit, max_stats.direct_hit);
+        max_stats.determination += bonus(Stat::Determination, max_stats.determination);
+        max_stats.spell_speed += bonus(Stat::SpellSpeed, max_stats.spell_speed);
+        max_stats.vitality += bonus(Stat::Vitality, max_stats.vitality);
+        max_stats.piety += bonus(Stat::Piety, max_stats.piety);
+    }
+    Some(max_stats)
+}
+
+// Every GCD (in hundredths of seconds) a gearset built from these items could have, fastest first.
+// This is a superset: some of these tiers might turn out to be unreachable.
+pub fn gcd_tiers(items: &[Item], base: &Stats) -> Vec<u32> {
+    let spell_speed = |item: &Item| item.stats.spell_speed;
+    let max_spell_speed = |item: &Item| {
+        let mut gearset = Gearset::default();
+        gearset.items[0] = item.clone();
+        let (possible_x, possible_ix) = gearset.possible_melds();
+        item.stats.spell_speed
+            + possible_x[MeldType::SpellSpeed as usize] * 36
+            + possible_ix[MeldType::SpellSpeed as usize] * 12
+    };
+
+    let (slowest, fastest) = ITEM_SLOTS.iter()
+        .map(|slot| {
+            // Both ring slots are filled from the ring list
+            let slot = if *slot == ItemSlot::RightRing { ItemSlot::LeftRing } else { *slot };
+            let slot_items = items.iter().filter(|item| item.slot == slot);
+            (
+                slot_items.clone().map(spell_speed).min().unwrap_or_default(),
+                slot_items.map(max_spell_speed).max().unwrap_or_default(),
+            )
+        })
+        .fold((base.spell_speed, base.spell_speed), |(slowest, fastest), (min, max)| (slowest + min, fastest + max));
+    let fastest = fastest + fastest / 10;
+
+    let gcd = |spell_speed| Stats { spell_speed, ..Default::default() }.gcd().0;
+    (gcd(fastest)..=gcd(slowest)).collect()
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use crate::data::*;
+use crate::ui::UiLink;
+use crate::solver::{CancellationToken, Constraints, Evaluator, Solution, Solver, SolverBuilder};
+
+#[derive(Debug, Clone)]
+pub struct ConstraintCost {
+    pub constraint: String,
+    // Compared with the unconstrained optimum, `None` if no gearset satisfies the constraint
+    pub dps_lost: Option<f64>,
+}
+
+// Runs another solver with the constraints, then without them and with each of them alone, to
+// report how much DPS every constraint costs. Every cost is an extra run of the inner solver.
+pub struct ConstraintCostSolver {
+    items: Vec<Item>,
+    ui_link: UiLink,
+    evaluator: Arc<dyn Evaluator + Send+Sync>,
+    constraints: Constraints,
+    solver: Box<SolverBuilder>,
+}
+
+impl ConstraintCostSolver {
+    pub fn new(
+        ui_link: UiLink,
+        evaluator: Arc<dyn Evaluator + Send+Sync>,
+        solver: impl Fn(Vec<Item>, Constraints) -> Arc<dyn Solver + Send+Sync> + Send+Sync + 'static,
+    ) -> Self {
+        Self {
+            items: Vec::default(),
+            ui_link,
+            evaluator,
+            constraints: Constraints::default(),
+            solver: Box::new(solver),
+        }
+    }
+
+    pub fn with_items(self, items: Vec<Item>) -> Self {
+        Self {
+            items,
+            ..self
+        }
+    }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    // Best DPS under these constraints, `None` if nothing satisfies them or the run was cancelled
+    fn best_dps(&self, constraints: Constraints, token: &CancellationToken) -> eyre::Result<Option<f64>> {
+        let solution = (self.solver)(self.items.clone(), constraints).solve(token)?;
+        Ok(solution.gearsets.first()
+            .filter(|_| !solution.partial)
+            .map(|gearset| self.evaluator.dps(gearset)))
+    }
+}
+
+impl Solver for ConstraintCostSolver {
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        let mut solution = (self.solver)(self.items.clone(), self.constraints.clone()).solve(token)?;
+        if solution.partial || !self.constraints.is_active() {
+            return Ok(solution);
+        }
+        let results: Vec<_> = solution.gearsets.iter()
+            .map(|gearset| (gearset.clone(), self.evaluator.dps(gearset)))
+            .collect();
+        let constrained_dps = results.first().map(|(_, dps)| *dps);
+
+        tracing::info!("Solving without constraints");
+        let unconstrained_dps = match self.best_dps(Constraints::default(), token)? {
+            Some(dps) => dps,
+            None => return Ok(solution),
+        };
+
+        let split = self.constraints.split();
+        let mut costs = Vec::new();
+        if split.len() > 1 {
+            for (label, constraints) in split {
+                // The cost runs replace the results in the UI while they go, put them back
+                self.ui_link.provisional_gearsets(results.clone())?;
+                tracing::info!("Solving with {} only", label);
+                let dps = self.best_dps(constraints, token)?;
+                if token.is_cancelled() {
+                    break;
+                }
+                costs.push(ConstraintCost {
+                    constraint: label,
+                    dps_lost: dps.map(|dps| unconstrained_dps - dps),
+                });
+            }
+        }
+        costs.push(ConstraintCost {
+            constraint: "All constraints".to_string(),
+            dps_lost: constrained_dps.map(|dps| unconstrained_dps - dps),
+        });
+
+        // Cancelling the cost runs doesn't make the results themselves partial
+        solution.constraint_costs = costs;
+        Ok(solution)
+    }
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.evaluator.dps(gearset)
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use crate::data::*;
+use crate::solver::Constraints;
+
+// More than enough to show, and keeps classes of interchangeable items from growing without bound
+const MAX_ALTERNATIVES: usize = 32;
+
+// Everything about a gearset without melds and food that the rest of the enumeration depends on.
+// Gearsets with the same key end up with the same stats for the same melds and food, so only one
+// of them has to be evaluated.
+#[derive(PartialEq, Eq, Hash)]
+struct StatKey {
+    stats: Stats,
+    possible_melds: (MatX, MatIX),
+    meld_slots: (u32, u32),
+    // A unique ring can't be picked again for the other ring slot
+    unique_rings: Vec<String>,
+    overmelds: u32,
+    unowned_items: u32,
+}
+
+impl StatKey {
+    fn new(gearset: &Gearset, constraints: &Constraints) -> Self {
+        let mut unique_rings: Vec<_> = gearset.items[ItemSlot::LeftRing as usize..=ItemSlot::RightRing as usize].iter()
+            .filter(|ring| ring.overmeldable == 0 && !ring.name.is_empty())
+            .map(|ring| ring.name.clone())
+            .collect();
+        unique_rings.sort();
+
+        Self {
+            stats: gearset.stats(),
+            possible_melds: gearset.possible_melds(),
+            meld_slots: gearset.meld_slots(),
+            unique_rings,
+            overmelds: gearset.overmelds(),
+            unowned_items: constraints.unowned_items(gearset),
+        }
+    }
+}
+
+// Groups the gearsets of an item enumeration stage by `StatKey`. The first gearset of a class goes
+// on to be evaluated, the item lists of the next ones are remembered as its alternatives. Each
+// class starts with the item list of its first gearset.
+pub struct EquivalenceClasses<'a> {
+    constraints: &'a Constraints,
+    classes: HashMap<StatKey, Vec<[Item; 11]>>,
+}
+
+impl<'a> EquivalenceClasses<'a> {
+    pub fn new(constraints: &'a Constraints) -> Self {
+        Self {
+            constraints,
+            classes: HashMap::new(),
+        }
+    }
+
+    pub fn first_of_class(&mut self, gearset: &Gearset) -> bool {
+        match self.classes.entry(StatKey::new(gearset, self.constraints)) {
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(vec![gearset.items.clone()]);
+                true
+            },
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                // The ring lists repeat each ring once per pair, so the same item list can come
+                // again. Its own alternatives from earlier stages are found again through its items.
+                let class = entry.get_mut();
+                if class.len() < MAX_ALTERNATIVES && !class.contains(&gearset.items) {
+                    class.push(gearset.items.clone());
+                }
+                false
+            },
+        }
+    }
+
+    // Set the alternatives of the gearsets that made it through the stage aside, by the items of
+    // the slots decided so far
+    pub fn set_aside(mut self, gearsets: &[Gearset], decided: Vec<ItemSlot>, alternatives: &mut SlotAlternatives) {
+        let kept = gearsets.iter()
+            .filter_map(|gearset| {
+                let mut class = self.classes.remove(&StatKey::new(gearset, self.constraints))?;
+                Some((decided_items(&gearset.items, &decided), class.split_off(1)))
+            })
+            .filter(|(_, class)| !class.is_empty())
+            .collect();
+        alternatives.stages.push((decided, kept));
+    }
+}
+
+// The alternatives of every item stage, kept out of the candidate gearsets: cloning them with every
+// candidate would cost more than evaluating it. They are attached once the items are all decided.
+#[derive(Default)]
+pub struct SlotAlternatives {
+    stages: Vec<(Vec<ItemSlot>, KeptClasses)>,
+}
+
+// Alternatives of the gearsets a stage kept, by the items of the slots decided at that stage
+type KeptClasses = HashMap<Vec<Item>, Vec<[Item; 11]>>;
+
+impl SlotAlternatives {
+    // Alternatives of a complete item list: those of each of its partial gearsets, then those of
+    // the alternatives themselves from earlier stages, with the slots decided later taken from it
+    pub fn of(&self, items: &[Item; 11]) -> Vec<[Item; 11]> {
+        let mut alternatives = Vec::new();
+        self.collect(items, self.stages.len(), &mut alternatives);
+        alternatives
+    }
+
+    fn collect(&self, items: &[Item; 11], stages: usize, alternatives: &mut Vec<[Item; 11]>) {
+        for (stage, (decided, classes)) in self.stages[..stages].iter().enumerate() {
+            let class = match classes.get(&decided_items(items, decided)) {
+                Some(class) => class,
+                None => continue,
+            };
+            for alternative in class {
+                if alternatives.len() >= MAX_ALTERNATIVES {
+                    return;
+                }
+                let mut full = items.clone();
+                for slot in decided {
+                    full[*slot as usize] = alternative[*slot as usize].clone();
+                }
+                if full == *items || alternatives.contains(&full) {
+                    continue;
+                }
+                alternatives.push(full.clone());
+                self.collect(&full, stage, alternatives);
+            }
+        }
+    }
+}
+
+fn decided_items(items: &[Item; 11], decided: &[ItemSlot]) -> Vec<Item> {
+    decided.iter()
+        .map(|slot| items[*slot as usize].clone())
+        .collect()
+}
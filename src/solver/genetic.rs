@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use crate::data::*;
+use crate::ui::UiLink;
+use crate::solver::{CancellationToken, Constraints, DiversityConfig, Evaluator, ItemPool, Solution, Solver, TopK, TopKExt, PUBLISH_INTERVAL};
+use crate::solver::pool::{repair_melds, swap_meld};
+
+#[derive(Clone)]
+pub struct GeneticConfig {
+    pub seed: u64,
+    pub population: usize,
+    pub generations: usize,
+    // Best individuals copied as is to the next generation
+    pub elitism: usize,
+    // Per gene: each slot, the food and the melds
+    pub mutation_rate: f64,
+    pub tournament_size: usize,
+    // Niching by clearing: among gearsets closer than the diversity's `min_item_swaps` (at least
+    // 1), only the best one keeps its fitness for selection and elitism. The population then
+    // spreads over distinct builds instead of converging on copies of the best one.
+    pub clearing: bool,
+}
+
+impl Default for GeneticConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            population: 200,
+            generations: 200,
+            elitism: 4,
+            mutation_rate: 0.05,
+            tournament_size: 3,
+            clearing: true,
+        }
+    }
+}
+
+// Subtracted from the fitness of the gearsets breaking the constraints. Dropping them outright
+// would empty the first generations, they still carry useful genes.
+const CONSTRAINT_PENALTY: f64 = 1e6;
+
+// Population-based search. Gearsets are genomes over `ITEM_SLOTS`, the food and the melds.
+//
+// The result is the whole final population (without duplicates), not just its best individual, so
+// that several near-optimal builds can be compared. Clearing is what keeps them apart, see
+// `GeneticConfig::clearing`.
+pub struct GeneticSolver {
+    items: Vec<Item>,
+    ui_link: UiLink,
+    evaluator: Arc<dyn Evaluator + Send+Sync>,
+    config: GeneticConfig,
+    constraints: Constraints,
+    diversity: DiversityConfig,
+}
+
+impl GeneticSolver {
+    pub fn new(ui_link: UiLink, evaluator: Arc<dyn Evaluator + Send+Sync>) -> Self {
+        Self {
+            items: Vec::default(),
+            ui_link,
+            evaluator,
+            config: GeneticConfig::default(),
+            constraints: Constraints::default(),
+            diversity: DiversityConfig::default(),
+        }
+    }
+
+    pub fn with_items(self, items: Vec<Item>) -> Self {
+        Self {
+            items,
+            ..self
+        }
+    }
+
+    pub fn with_config(self, config: GeneticConfig) -> Self {
+        Self {
+            config,
+            ..self
+        }
+    }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    pub fn with_diversity(self, diversity: DiversityConfig) -> Self {
+        Self {
+            diversity,
+            ..self
+        }
+    }
+
+    // Tournaments are decided on the fitness left after clearing
+    fn select<'a>(&self, population: &'a [(Gearset, f64)], cleared: &[f64], rng: &mut StdRng) -> &'a Gearset {
+        (0..self.config.tournament_size.max(1))
+            .map(|_| rng.gen_range(0..population.len()))
+            .max_by(|a, b| cleared[*a].partial_cmp(&cleared[*b]).unwrap())
+            .map(|index| &population[index].0)
+            .unwrap()
+    }
+
+    // Fitness for selection, of a population sorted best first: gearsets too close to a better one
+    // are cleared, the best of each niche keeps its own
+    fn clear(&self, population: &[(Gearset, f64)]) -> Vec<f64> {
+        if !self.config.clearing {
+            return population.iter().map(|(_, fitness)| *fitness).collect();
+        }
+        let radius = self.diversity.min_item_swaps.max(1);
+        let mut winners: Vec<&Gearset> = Vec::new();
+        population.iter()
+            .map(|(gearset, fitness)| {
+                if winners.iter().any(|winner| winner.item_swaps(gearset) < radius) {
+                    f64::NEG_INFINITY
+                } else {
+                    winners.push(gearset);
+                    *fitness
+                }
+            })
+            .collect()
+    }
+
+    // Uniform crossover: every slot, the food and each kind of meld come from either parent
+    fn crossover(&self, pool: &ItemPool, a: &Gearset, b: &Gearset, rng: &mut StdRng) -> Gearset {
+        let mut child = a.clone();
+        for slot in ITEM_SLOTS {
+            if rng.gen_bool(0.5) {
+                child.items[slot as usize] = b.items[slot as usize].clone();
+            }
+        }
+        while !child.is_valid() {
+            // Both parents brought the same unique ring
+            child.items[ItemSlot::RightRing as usize] = pool.items(ItemSlot::RightRing).choose(rng).unwrap().clone();
+        }
+        if rng.gen_bool(0.5) {
+            child.food = b.food.clone();
+        }
+        if rng.gen_bool(0.5) {
+            child.meld_x = b.meld_x;
+        }
+        if rng.gen_bool(0.5) {
+            child.meld_ix = b.meld_ix;
+        }
+        repair_melds(&mut child, rng);
+        child
+    }
+
+    fn mutate(&self, pool: &ItemPool, gearset: &mut Gearset, rng: &mut StdRng) {
+        let rate = self.config.mutation_rate;
+        for slot in ITEM_SLOTS {
+            if rng.gen_bool(rate) {
+                let previous = std::mem::replace(
+                    &mut gearset.items[slot as usize],
+                    pool.items(slot).choose(rng).unwrap().clone(),
+                );
+                if !gearset.is_valid() {
+                    gearset.items[slot as usize] = previous;
+                }
+            }
+        }
+        if rng.gen_bool(rate) {
+            gearset.food = pool.food().choose(rng).unwrap().clone();
+        }
+        repair_melds(gearset, rng);
+        let (possible_x, possible_ix) = gearset.possible_melds();
+        if rng.gen_bool(rate) {
+            swap_meld(&mut gearset.meld_x, &possible_x, rng);
+        }
+        if rng.gen_bool(rate) {
+            swap_meld(&mut gearset.meld_ix, &possible_ix, rng);
+        }
+    }
+
+    fn publish(&self, population: &[(Gearset, f64)]) -> eyre::Result<()> {
+        self.ui_link.provisional_gearsets(population.iter()
+            .filter(|(gearset, _)| self.constraints.accepts(gearset))
+            .take(100)
+            .cloned()
+            .collect()
+        )
+    }
+}
+
+impl Solver for GeneticSolver {
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        self.ui_link.stage("Loading items...", 0)?;
+        let pool = ItemPool::new(&self.items);
+        if let Err(error) = pool.check() {
+            self.ui_link.message(format!("ERROR: {}", error))?;
+            return Err(error);
+        }
+
+        let population_size = self.config.population.max(2);
+        let elitism = self.config.elitism.min(population_size);
+        let mut rng = StdRng::seed_from_u64(self.config.seed);
+
+        // Every generation counts its whole population. Elites are not evaluated again, but with
+        // clearing there can be fewer of them than `elitism`.
+        let total = population_size * (self.config.generations + 1);
+        self.ui_link.stage("Evolving...", total)?;
+        let progress = self.ui_link.progress();
+        let evaluate = |gearset: Gearset| {
+            progress.tick();
+            let mut fitness = self.evaluator.dps(&gearset);
+            if !self.constraints.accepts(&gearset) {
+                fitness -= CONSTRAINT_PENALTY;
+            }
+            (gearset, fitness)
+        };
+        let sort = |population: &mut Vec<(Gearset, f64)>| {
+            population.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+        };
+
+        // Seed the population with the greedy gearset so we never do worse than it
+        let mut population: Vec<_> = std::iter::once(pool.greedy_gearset(self.evaluator.as_ref()))
+            .chain((1..population_size).map(|_| pool.random_gearset(&mut rng)))
+            .map(evaluate)
+            .collect();
+        sort(&mut population);
+
+        let mut last_publish = Instant::now();
+        let mut partial = false;
+        for _ in 0..self.config.generations {
+            if token.is_cancelled() {
+                partial = true;
+                break;
+            }
+
+            // Cleared gearsets are not elites either, so the elites are distinct builds
+            let cleared = self.clear(&population);
+            let elites: Vec<_> = population.iter()
+                .zip(&cleared)
+                .filter(|(_, fitness)| fitness.is_finite())
+                .map(|(individual, _)| individual.clone())
+                .take(elitism)
+                .collect();
+            elites.iter().for_each(|_| progress.tick());
+            let children: Vec<_> = (elites.len()..population_size)
+                .map(|_| {
+                    let a = self.select(&population, &cleared, &mut rng);
+                    let b = self.select(&population, &cleared, &mut rng);
+                    let mut child = self.crossover(&pool, a, b, &mut rng);
+                    self.mutate(&pool, &mut child, &mut rng);
+                    child
+                })
+                .collect();
+            population = elites.into_iter().chain(children.into_iter().map(evaluate)).collect();
+            sort(&mut population);
+
+            if last_publish.elapsed() >= PUBLISH_INTERVAL {
+                last_publish = Instant::now();
+                self.publish(&population)?;
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let gearsets = population.into_iter()
+            .map(|(gearset, _)| gearset)
+            .filter(|gearset| self.constraints.accepts(gearset))
+            .filter(|gearset| seen.insert(gearset.clone()))
+            .top_k(TopK::new(population_size, self.evaluator.clone()).diverse(self.diversity.min_item_swaps));
+
+        Ok(Solution::new(gearsets, partial))
+    }
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.evaluator.dps(gearset)
+    }
+}
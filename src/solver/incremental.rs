@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::data::*;
+use crate::ui::UiLink;
+use crate::solver::{CancellationToken, Constraints, Evaluator, Solution, Solver, SolverBuilder};
+
+#[derive(Debug, Clone)]
+pub struct ItemImpact {
+    pub item: String,
+    // Best gearset with the item against the previous best, positive if it enters the BiS. `None`
+    // if no gearset with the item satisfies the constraints.
+    //
+    // Heuristic: the item is only tried with the items of the previous results, see
+    // `IncrementalSolver`. The gain can be higher with other items, and `None` only means none of
+    // these gearsets works.
+    pub dps_gain: Option<f64>,
+}
+
+// Same item, whichever ring slot it is in
+fn same_item(a: &Item, b: &Item) -> bool {
+    a.name == b.name && a.stats == b.stats && a.meld_slots == b.meld_slots && a.overmeldable == b.overmeldable
+}
+
+// Re-solves after a few items were added or changed, starting from the results of the previous run
+// instead of from scratch. For each new item, the inner solver only gets the new item in its slot
+// and, in the other slots, the items the previous results use: the previous results are the
+// gearsets the new item has to beat, and the ones it would most likely replace a piece of.
+//
+// That neighbourhood is a heuristic, not a bound: an item that only pays off together with items no
+// previous result uses is missed, and its impact is underestimated. A run from scratch settles it.
+//
+// Without a new item, or without any previous result still possible with the new items, this is a
+// plain run of the inner solver.
+pub struct IncrementalSolver {
+    items: Vec<Item>,
+    ui_link: UiLink,
+    evaluator: Arc<dyn Evaluator + Send+Sync>,
+    constraints: Constraints,
+    previous_items: Vec<Item>,
+    previous_gearsets: Vec<Gearset>,
+    solver: Box<SolverBuilder>,
+}
+
+impl IncrementalSolver {
+    pub fn new(
+        ui_link: UiLink,
+        evaluator: Arc<dyn Evaluator + Send+Sync>,
+        solver: impl Fn(Vec<Item>, Constraints) -> Arc<dyn Solver + Send+Sync> + Send+Sync + 'static,
+    ) -> Self {
+        Self {
+            items: Vec::default(),
+            ui_link,
+            evaluator,
+            constraints: Constraints::default(),
+            previous_items: Vec::default(),
+            previous_gearsets: Vec::default(),
+            solver: Box::new(solver),
+        }
+    }
+
+    pub fn with_items(self, items: Vec<Item>) -> Self {
+        Self {
+            items,
+            ..self
+        }
+    }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    // Items and results of the run to start from
+    pub fn with_previous(self, previous_items: Vec<Item>, previous_gearsets: Vec<Gearset>) -> Self {
+        Self {
+            previous_items,
+            previous_gearsets,
+            ..self
+        }
+    }
+
+    // The items of the previous results, with `item` alone in its slot
+    fn neighbourhood(&self, item: &Item, previous: &[Gearset]) -> Vec<Item> {
+        let mut items: Vec<Item> = Vec::new();
+        let used = previous.iter()
+            .flat_map(|gearset| gearset.items.iter())
+            .filter(|used| !used.name.is_empty());
+        for used in used {
+            // Rings are listed once, as left rings
+            let slot = if used.slot == ItemSlot::RightRing { ItemSlot::LeftRing } else { used.slot };
+            if !items.iter().any(|listed| same_item(listed, used)) {
+                items.push(Item { slot, ..used.clone() });
+            }
+        }
+        // Any food goes with any gear, and there are few of them
+        let food = self.items.iter().filter(|food| food.slot == ItemSlot::Food).cloned();
+        items.retain(|listed| listed.slot != item.slot || listed.slot == ItemSlot::LeftRing);
+        items.extend(food.filter(|food| item.slot != ItemSlot::Food || same_item(food, item)));
+        if item.slot != ItemSlot::Food {
+            items.push(item.clone());
+        }
+        items
+    }
+}
+
+impl Solver for IncrementalSolver {
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        let is_available = |item: &Item| item.name.is_empty() || self.items.iter().any(|new| same_item(new, item));
+        let previous: Vec<_> = self.previous_gearsets.iter()
+            .filter(|gearset| gearset.items.iter().chain(std::iter::once(&gearset.food)).all(is_available))
+            .filter(|gearset| self.constraints.accepts(gearset))
+            .cloned()
+            .collect();
+        let new_items: Vec<_> = self.items.iter()
+            .filter(|item| !self.previous_items.iter().any(|previous| same_item(previous, item)))
+            .collect();
+        // With only removed items, the previous results don't tell what replaces them
+        if previous.is_empty() || new_items.is_empty() {
+            tracing::info!("No new item or no previous result to start from, solving from scratch");
+            return (self.solver)(self.items.clone(), self.constraints.clone()).solve(token);
+        }
+        let previous_best = previous.iter()
+            .map(|gearset| self.evaluator.dps(gearset))
+            .fold(f64::NEG_INFINITY, f64::max);
+
+        let mut gearsets: HashSet<_> = previous.iter().cloned().collect();
+        let mut impacts = Vec::new();
+        let mut partial = false;
+        for (index, item) in new_items.iter().enumerate() {
+            if token.is_cancelled() {
+                partial = true;
+                break;
+            }
+            tracing::info!("Solving with {} ({}/{})", item.name, index + 1, new_items.len());
+            let items = self.neighbourhood(item, &previous);
+            let solution = (self.solver)(items, self.constraints.clone()).solve(token)?;
+            if solution.partial {
+                partial = true;
+                break;
+            }
+            // Rings can't be forced in, the results might have two other rings
+            let with_item: Vec<_> = solution.gearsets.into_iter()
+                .filter(|gearset| gearset.items.iter().chain(std::iter::once(&gearset.food)).any(|used| same_item(used, item)))
+                .collect();
+            let best = with_item.iter()
+                .map(|gearset| self.evaluator.dps(gearset))
+                .reduce(f64::max);
+            impacts.push(ItemImpact {
+                item: item.name.clone(),
+                dps_gain: best.map(|best| best - previous_best),
+            });
+            gearsets.extend(with_item);
+
+            // The inner solver only showed the gearsets with the new item
+            let mut results: Vec<_> = gearsets.iter()
+                .map(|gearset| (gearset.clone(), self.evaluator.dps(gearset)))
+                .collect();
+            results.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+            self.ui_link.provisional_gearsets(results)?;
+        }
+
+        let mut gearsets: Vec<_> = gearsets.into_iter()
+            .map(|gearset| (self.evaluator.dps(&gearset), gearset))
+            .collect();
+        gearsets.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap());
+        gearsets.truncate(self.previous_gearsets.len());
+
+        let mut solution = Solution::new(gearsets.into_iter().map(|(_, gearset)| gearset).collect(), partial);
+        solution.item_impacts = impacts;
+        Ok(solution)
+    }
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.evaluator.dps(gearset)
+    }
+}
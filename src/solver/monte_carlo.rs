@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::damage::{self, HitType, VARIANCE};
+use crate::data::Stats;
+use crate::solver::{Evaluator, TickPhase, Timeline};
+
+// Server tick phases a run picks from when the phase is random, every 0.1s
+const TICK_PHASES: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DpsStatistic {
+    Mean,
+    // Between 0 and 100: with 25, 3 runs out of 4 do better
+    Percentile(f64),
+}
+
+impl std::fmt::Display for DpsStatistic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DpsStatistic::Mean => write!(f, "Mean"),
+            DpsStatistic::Percentile(percentile) => write!(f, "p{}", percentile),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MonteCarloConfig {
+    pub runs: usize,
+    pub seed: u64,
+    // What the solvers rank gearsets by
+    pub rank_by: DpsStatistic,
+}
+
+impl Default for MonteCarloConfig {
+    fn default() -> Self {
+        Self {
+            runs: 1000,
+            seed: 0,
+            rank_by: DpsStatistic::Mean,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DpsDistribution {
+    pub mean: f64,
+    pub std_dev: f64,
+    pub p25: f64,
+    pub p50: f64,
+    pub p75: f64,
+    pub p95: f64,
+}
+
+// Simulates the fight of a `Timeline` many times, rolling crits, direct hits and the damage
+// variance of every hit, and ranks gearsets by the mean or a percentile of the DPS of the runs.
+//
+// Every stat line is simulated with the same seed, so the same stats always get the same DPS. Two
+// gearsets only share their rolls while their fights have the same hits: with different GCDs the
+// casts differ, the rolls land on other hits and the comparison keeps some noise. This is a lot
+// slower than the other evaluators: a run goes through every hit of the fight.
+pub struct MonteCarloEvaluator {
+    timeline: Arc<Timeline>,
+    config: MonteCarloConfig,
+}
+
+impl MonteCarloEvaluator {
+    pub fn new(timeline: Arc<Timeline>, config: MonteCarloConfig) -> Self {
+        Self {
+            timeline,
+            config,
+        }
+    }
+
+    // DPS of every run, sorted
+    fn runs(&self, stats: &Stats) -> Vec<f64> {
+        let hits: Vec<_> = match self.timeline.dot_config().tick_phase {
+            TickPhase::Fixed(phase) => vec![self.timeline.hits(stats, phase)],
+            TickPhase::Random => (0..TICK_PHASES)
+                .map(|phase| self.timeline.hits(stats, phase as f64 * 3.0 / TICK_PHASES as f64))
+                .collect(),
+        };
+        let mut rng = StdRng::seed_from_u64(self.config.seed);
+        let mut runs: Vec<_> = (0..self.config.runs)
+            .map(|_| {
+                let mut total = 0.0;
+                let hits = &hits[rng.gen_range(0..hits.len())];
+                for hit in hits.iter() {
+                    for _ in 0..hit.count {
+                        let hit_type = HitType {
+                            critical: rng.gen::<f64>() < hit.crit_rate,
+                            direct_hit: rng.gen::<f64>() < hit.dh_rate,
+                        };
+                        let variance = rng.gen_range(VARIANCE);
+                        total += damage::damage(&hit.stats, &hit.action, hit_type, variance, &hit.buffs) as f64;
+                    }
+                }
+                total / self.timeline.end()
+            })
+            .collect();
+        runs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        runs
+    }
+
+    pub fn distribution(&self, stats: &Stats) -> DpsDistribution {
+        let runs = self.runs(stats);
+        let mean = mean(&runs);
+        let variance = runs.iter().map(|dps| (dps - mean).powi(2)).sum::<f64>() / runs.len() as f64;
+        DpsDistribution {
+            mean,
+            std_dev: variance.sqrt(),
+            p25: percentile(&runs, 25.0),
+            p50: percentile(&runs, 50.0),
+            p75: percentile(&runs, 75.0),
+            p95: percentile(&runs, 95.0),
+        }
+    }
+}
+
+fn mean(runs: &[f64]) -> f64 {
+    runs.iter().sum::<f64>() / runs.len() as f64
+}
+
+// Linear interpolation between the closest runs
+fn percentile(sorted_runs: &[f64], percentile: f64) -> f64 {
+    if sorted_runs.is_empty() {
+        return 0.0;
+    }
+    let position = (percentile / 100.0).clamp(0.0, 1.0) * (sorted_runs.len() - 1) as f64;
+    let below = sorted_runs[position.floor() as usize];
+    let above = sorted_runs[position.ceil() as usize];
+    below + (above - below) * position.fract()
+}
+
+impl Evaluator for MonteCarloEvaluator {
+    fn stats_dps(&self, stats: &Stats) -> f64 {
+        let runs = self.runs(stats);
+        match self.config.rank_by {
+            DpsStatistic::Mean => mean(&runs),
+            DpsStatistic::Percentile(rank_by) => percentile(&runs, rank_by),
+        }
+    }
+
+    fn settings(&self) -> String {
+        format!("Monte Carlo {:?} over {}", self.config, self.timeline.settings())
+    }
+}
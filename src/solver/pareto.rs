@@ -0,0 +1,208 @@
+use std::sync::Arc;
+
+use crate::data::*;
+use crate::ui::UiLink;
+use crate::solver::{CancellationToken, Constraints, Evaluator, GcdConstraint, Solution, Solver, SolverBuilder, StatBound};
+
+// What the Pareto front trades DPS against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    Piety,
+    Hp,
+    Gcd,
+    Overmelds,
+    UnownedItems,
+}
+
+pub const OBJECTIVES: [Objective; 5] = [
+    Objective::Piety,
+    Objective::Hp,
+    Objective::Gcd,
+    Objective::Overmelds,
+    Objective::UnownedItems,
+];
+
+impl Objective {
+    // Unowned items are counted against `constraints.owned_items`
+    pub fn value(&self, gearset: &Gearset, constraints: &Constraints) -> u32 {
+        match self {
+            Objective::Piety => gearset.stats().piety(),
+            Objective::Hp => gearset.stats().hp(),
+            Objective::Gcd => gearset.stats().gcd().0,
+            Objective::Overmelds => gearset.overmelds(),
+            Objective::UnownedItems => constraints.unowned_items(gearset),
+        }
+    }
+
+    pub fn higher_is_better(&self) -> bool {
+        matches!(self, Objective::Piety | Objective::Hp)
+    }
+
+    // Worse than any gearset
+    fn worst(&self) -> u32 {
+        if self.higher_is_better() {
+            0
+        } else {
+            u32::MAX
+        }
+    }
+
+    // The constraints, plus the objective having to be strictly better than `value`. `None` if
+    // nothing can be better.
+    pub fn tighten(&self, constraints: Constraints, value: u32) -> Option<Constraints> {
+        let mut constraints = constraints;
+        match self {
+            Objective::Piety | Objective::Hp => {
+                let stat = if *self == Objective::Piety { Stat::Piety } else { Stat::Hp };
+                constraints.stat_bounds.push(StatBound {
+                    min: Some(value.checked_add(1)?),
+                    ..StatBound::new(stat)
+                });
+            },
+            Objective::Gcd => {
+                // An exact tier from the user wins, there is nothing faster to look for
+                if constraints.gcd == Some(GcdConstraint::Equal) {
+                    return None;
+                }
+                let max = value.checked_sub(1)?;
+                constraints.gcd_target = match constraints.gcd {
+                    Some(GcdConstraint::AtMost) => constraints.gcd_target.min(max),
+                    _ => max,
+                };
+                constraints.gcd = Some(GcdConstraint::AtMost);
+            },
+            Objective::Overmelds => {
+                let max = value.checked_sub(1)?;
+                constraints.max_overmelds = Some(constraints.max_overmelds.map_or(max, |current| current.min(max)));
+            },
+            Objective::UnownedItems => {
+                let max = value.checked_sub(1)?;
+                constraints.max_unowned_items = Some(constraints.max_unowned_items.map_or(max, |current| current.min(max)));
+            },
+        }
+        Some(constraints)
+    }
+
+    // Constraints for pruning the items: items only better on the objective have to be kept
+    pub fn pruning_constraints(&self, constraints: Constraints) -> Constraints {
+        self.tighten(constraints.clone(), self.worst()).unwrap_or(constraints)
+    }
+}
+
+impl std::fmt::Display for Objective {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Objective::Piety => write!(f, "Piety"),
+            Objective::Hp => write!(f, "HP"),
+            Objective::Gcd => write!(f, "GCD"),
+            Objective::Overmelds => write!(f, "Overmelds"),
+            Objective::UnownedItems => write!(f, "Unowned items"),
+        }
+    }
+}
+
+// Pareto front of DPS against a second objective, with the epsilon-constraint method: solve for
+// DPS, then again requiring a strictly better objective than the last result, until nothing
+// satisfies the constraints anymore. Each point of the front is one run of the inner solver.
+pub struct ParetoSolver {
+    items: Vec<Item>,
+    ui_link: UiLink,
+    evaluator: Arc<dyn Evaluator + Send+Sync>,
+    constraints: Constraints,
+    objective: Objective,
+    solver: Box<SolverBuilder>,
+}
+
+impl ParetoSolver {
+    pub fn new(
+        ui_link: UiLink,
+        evaluator: Arc<dyn Evaluator + Send+Sync>,
+        solver: impl Fn(Vec<Item>, Constraints) -> Arc<dyn Solver + Send+Sync> + Send+Sync + 'static,
+    ) -> Self {
+        Self {
+            items: Vec::default(),
+            ui_link,
+            evaluator,
+            constraints: Constraints::default(),
+            objective: Objective::Piety,
+            solver: Box::new(solver),
+        }
+    }
+
+    pub fn with_items(self, items: Vec<Item>) -> Self {
+        Self {
+            items,
+            ..self
+        }
+    }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    pub fn with_objective(self, objective: Objective) -> Self {
+        Self {
+            objective,
+            ..self
+        }
+    }
+}
+
+impl Solver for ParetoSolver {
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        let mut front = Vec::new();
+        let mut constraints = Some(self.constraints.clone());
+        let mut partial = false;
+        while let Some(current) = constraints {
+            if token.is_cancelled() {
+                partial = true;
+                break;
+            }
+            // The number of points isn't known in advance, each one gets its own stage
+            match front.last() {
+                None => self.ui_link.stage("Pareto front: solving for DPS...", 0)?,
+                Some((last, _)) => self.ui_link.stage(format!(
+                    "Pareto front: {} points, last {} = {}, solving for a better one...",
+                    front.len(), self.objective, self.objective.value(last, &self.constraints),
+                ), 0)?,
+            }
+            let solution = (self.solver)(self.items.clone(), current.clone()).solve(token)?;
+            // A cancelled run might not have reached its melds yet
+            if solution.partial {
+                partial = true;
+                break;
+            }
+            let best = match solution.gearsets.into_iter().next() {
+                Some(best) => best,
+                None => break,
+            };
+            let value = self.objective.value(&best, &self.constraints);
+            tracing::info!("Pareto front: {} = {}", self.objective, value);
+            front.push((best.clone(), self.evaluator.dps(&best)));
+            // The inner solver only showed its own results
+            self.ui_link.provisional_gearsets(front.clone())?;
+
+            constraints = self.objective.tighten(current, value);
+        }
+
+        // With a heuristic inner solver, a later point can beat an earlier one on both objectives
+        let objective = |gearset: &Gearset| {
+            let value = self.objective.value(gearset, &self.constraints) as i64;
+            if self.objective.higher_is_better() { value } else { -value }
+        };
+        let gearsets = front.iter()
+            .filter(|(gearset, dps)| !front.iter().any(|(other, other_dps)| {
+                other_dps > dps && objective(other) >= objective(gearset)
+            }))
+            .map(|(gearset, _)| gearset.clone())
+            .collect();
+        Ok(Solution::new(gearsets, partial))
+    }
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.evaluator.dps(gearset)
+    }
+}
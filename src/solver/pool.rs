@@ -0,0 +1,194 @@
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+use crate::data::*;
+use crate::solver::{Evaluator, SAGE_BASE};
+
+// Items sorted by slot, for the solvers that build gearsets piece by piece instead of enumerating
+// them.
+pub struct ItemPool {
+    // Indexed by `ItemSlot`. Both ring slots get the whole ring list.
+    gear: Vec<Vec<Item>>,
+    food: Vec<Item>,
+}
+
+impl ItemPool {
+    pub fn new(items: &[Item]) -> Self {
+        let mut gear = vec![Vec::new(); ITEM_SLOTS.len()];
+        let mut food = Vec::new();
+        for item in items {
+            match item.slot {
+                ItemSlot::Food => food.push(item.clone()),
+                ItemSlot::LeftRing | ItemSlot::RightRing => {
+                    for slot in [ItemSlot::LeftRing, ItemSlot::RightRing] {
+                        let mut ring = item.clone();
+                        ring.slot = slot;
+                        gear[slot as usize].push(ring);
+                    }
+                },
+                slot => gear[slot as usize].push(item.clone()),
+            }
+        }
+
+        Self {
+            gear,
+            food,
+        }
+    }
+
+    pub fn items(&self, slot: ItemSlot) -> &[Item] {
+        &self.gear[slot as usize]
+    }
+
+    pub fn food(&self) -> &[Item] {
+        &self.food
+    }
+
+    // Every slot needs at least one item to build a gearset
+    pub fn check(&self) -> eyre::Result<()> {
+        for slot in ITEM_SLOTS {
+            if self.items(slot).is_empty() {
+                return Err(eyre::eyre!("No item for slot {}", slot));
+            }
+        }
+        if self.food.is_empty() {
+            return Err(eyre::eyre!("No food"));
+        }
+        let rings = self.items(ItemSlot::LeftRing);
+        if rings.len() < 2 && !rings.iter().any(|ring| ring.overmeldable != 0) {
+            return Err(eyre::eyre!("Not enough rings to fill both ring slots"));
+        }
+        Ok(())
+    }
+
+    pub fn random_gearset(&self, rng: &mut impl Rng) -> Gearset {
+        let mut gearset = Gearset {
+            base: SAGE_BASE,
+            ..Default::default()
+        };
+        loop {
+            for slot in ITEM_SLOTS {
+                gearset.items[slot as usize] = self.items(slot).choose(rng).unwrap().clone();
+            }
+            if gearset.is_valid() {
+                break;
+            }
+        }
+        gearset.food = self.food.choose(rng).unwrap().clone();
+        repair_melds(&mut gearset, rng);
+        gearset
+    }
+
+    // Best item slot by slot, then best food, then best materia one at a time
+    pub fn greedy_gearset(&self, evaluator: &dyn Evaluator) -> Gearset {
+        let mut gearset = Gearset {
+            base: SAGE_BASE,
+            ..Default::default()
+        };
+        for slot in ITEM_SLOTS {
+            gearset.items[slot as usize] = self.items(slot).iter()
+                .map(|item| {
+                    let mut candidate = gearset.clone();
+                    candidate.items[slot as usize] = item.clone();
+                    candidate
+                })
+                .filter(Gearset::is_valid)
+                .map(|candidate| (evaluator.dps(&candidate), candidate))
+                .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+                .map(|(_, candidate)| candidate.items[slot as usize].clone())
+                .unwrap();
+        }
+        gearset.food = self.food.iter()
+            .max_by(|a, b| {
+                let mut with_a = gearset.clone();
+                with_a.food = (*a).clone();
+                let mut with_b = gearset.clone();
+                with_b.food = (*b).clone();
+                evaluator.dps(&with_a).partial_cmp(&evaluator.dps(&with_b)).unwrap()
+            })
+            .unwrap()
+            .clone();
+        greedy_melds(&mut gearset, evaluator);
+        gearset
+    }
+}
+
+// Fill the free meld slots one materia at a time, always picking the one that adds the most DPS.
+// Materias already melded stay.
+pub fn greedy_melds(gearset: &mut Gearset, evaluator: &dyn Evaluator) {
+    let (possible_x, possible_ix) = gearset.possible_melds();
+    let (slots_x, slots_ix) = gearset.meld_slots();
+
+    for _ in gearset.meld_x.iter().sum::<u32>()..slots_x {
+        let best = (0..MeldType::Number as usize)
+            .filter(|meld| gearset.meld_x[*meld] < possible_x[*meld])
+            .max_by(|a, b| {
+                let mut with_a = gearset.clone();
+                with_a.meld_x[*a] += 1;
+                let mut with_b = gearset.clone();
+                with_b.meld_x[*b] += 1;
+                evaluator.dps(&with_a).partial_cmp(&evaluator.dps(&with_b)).unwrap()
+            });
+        match best {
+            Some(meld) => gearset.meld_x[meld] += 1,
+            None => break,
+        }
+    }
+
+    for _ in gearset.meld_ix.iter().sum::<u32>()..slots_ix {
+        let best = (0..MeldType::Number as usize)
+            .filter(|meld| gearset.meld_ix[*meld] < possible_ix[*meld])
+            .max_by(|a, b| {
+                let mut with_a = gearset.clone();
+                with_a.meld_ix[*a] += 1;
+                let mut with_b = gearset.clone();
+                with_b.meld_ix[*b] += 1;
+                evaluator.dps(&with_a).partial_cmp(&evaluator.dps(&with_b)).unwrap()
+            });
+        match best {
+            Some(meld) => gearset.meld_ix[meld] += 1,
+            None => break,
+        }
+    }
+}
+
+// After an item swap, the current melds might not fit anymore: drop the materias that can't be
+// melded and fill the free slots at random.
+pub fn repair_melds(gearset: &mut Gearset, rng: &mut impl Rng) {
+    let (possible_x, possible_ix) = gearset.possible_melds();
+    let (slots_x, slots_ix) = gearset.meld_slots();
+    repair_meld_kind(&mut gearset.meld_x, &possible_x, slots_x, rng);
+    repair_meld_kind(&mut gearset.meld_ix, &possible_ix, slots_ix, rng);
+}
+
+fn repair_meld_kind(melds: &mut MatX, possible: &MatX, slots: u32, rng: &mut impl Rng) {
+    for (meld, possible) in melds.iter_mut().zip(possible.iter()) {
+        *meld = (*meld).min(*possible);
+    }
+    while melds.iter().sum::<u32>() > slots {
+        let candidates: Vec<_> = (0..melds.len()).filter(|meld| melds[*meld] > 0).collect();
+        melds[*candidates.choose(rng).unwrap()] -= 1;
+    }
+    while melds.iter().sum::<u32>() < slots {
+        let candidates: Vec<_> = (0..melds.len()).filter(|meld| melds[*meld] < possible[*meld]).collect();
+        match candidates.choose(rng) {
+            Some(meld) => melds[*meld] += 1,
+            // Not enough room for all the materias, leave the slots empty
+            None => break,
+        }
+    }
+}
+
+// Move one materia from a meld type to another, if there is room for it
+pub fn swap_meld(melds: &mut MatX, possible: &MatX, rng: &mut impl Rng) -> bool {
+    let from: Vec<_> = (0..melds.len()).filter(|meld| melds[*meld] > 0).collect();
+    let to: Vec<_> = (0..melds.len()).filter(|meld| melds[*meld] < possible[*meld]).collect();
+    match (from.choose(rng), to.choose(rng)) {
+        (Some(from), Some(to)) if from != to => {
+            melds[*from] -= 1;
+            melds[*to] += 1;
+            true
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,221 @@
+use crate::data::*;
+use crate::solver::Constraints;
+
+// Materia values, same as the ones used in `Stats::apply_materias`
+const MATERIA_X: u32 = 36;
+const MATERIA_IX: u32 = 12;
+
+#[derive(Debug, Clone)]
+pub struct PrunedItem {
+    pub item: Item,
+    pub reason: String,
+}
+
+impl Item {
+    // Same slot layout as `Gearset::meld_slots`
+    fn meld_budget(&self) -> u32 {
+        if self.slot == ItemSlot::Food {
+            0
+        } else if self.overmeldable == 0 {
+            self.meld_slots * MATERIA_X
+        } else {
+            (self.meld_slots + 1) * MATERIA_X + (5 - self.meld_slots - 1) * MATERIA_IX
+        }
+    }
+
+    // Sum of the DPS substats, plus whatever the melds could add on top
+    pub fn substat_budget(&self) -> u32 {
+        self.stats.critical
+            + self.stats.direct_hit
+            + self.stats.determination
+            + self.stats.spell_speed
+            + self.meld_budget()
+    }
+
+    // `self` dominates `other` if it is at least as good on weapon damage, mind, every DPS
+    // substat taken separately and the meld-adjusted substat budget.
+    //
+    // Comparing substats one by one keeps the pass conservative: a crit/det item is never
+    // pruned in favor of a DH/SpS one just because the latter has a bigger budget.
+    //
+    // Constraints change what "at least as good" means: a stat with an upper bound has to be
+    // equal, and a stat with a lower bound is compared even if it doesn't add DPS.
+    pub fn dominates(&self, other: &Self, constraints: &Constraints) -> bool {
+        self.slot == other.slot
+            && self.stats.weapon_damage >= other.stats.weapon_damage
+            && self.stats.mind >= other.stats.mind
+            && self.stats.critical >= other.stats.critical
+            && self.stats.direct_hit >= other.stats.direct_hit
+            && self.stats.determination >= other.stats.determination
+            && self.stats.spell_speed >= other.stats.spell_speed
+            && self.substat_budget() >= other.substat_budget()
+            && STATS.iter()
+                .filter(|stat| stat.item_stat() == **stat)
+                .all(|stat| {
+                    let (value, other_value) = (stat.value(&self.stats), stat.value(&other.stats));
+                    (!constraints.needs(*stat) || value >= other_value)
+                        && (!constraints.caps(*stat) || value <= other_value)
+                })
+            && (constraints.max_overmelds.is_none() || self.overmelds() <= other.overmelds())
+            && (constraints.max_unowned_items.is_none()
+                || constraints.owned_items.contains(&self.name)
+                || !constraints.owned_items.contains(&other.name))
+    }
+
+    fn can_be_worn_twice(&self) -> bool {
+        // Same rule as `Gearset::is_valid`: unique rings can't be worn twice
+        self.overmeldable != 0
+    }
+}
+
+// Remove the items that are Pareto-dominated by another item of the same slot.
+//
+// Items that are strictly identical are deduplicated, the first one is kept.
+// Rings need special care: both rings are picked from the same list, so a ring can only be pruned
+// if there are 2 rings dominating it (or one that can be worn twice).
+pub fn prune_dominated(items: Vec<Item>, constraints: &Constraints) -> (Vec<Item>, Vec<PrunedItem>) {
+    let verdicts: Vec<_> = items.iter().enumerate()
+        .map(|(index, item)| {
+            let dominators: Vec<_> = items.iter().enumerate()
+                .filter(|(other_index, other)| {
+                    *other_index != index
+                        && other.dominates(item, constraints)
+                        // Equal items dominate each other, only keep the first one
+                        && (!item.dominates(other, constraints) || *other_index < index)
+                })
+                .map(|(_, other)| other)
+                .collect();
+
+            match (item.slot == ItemSlot::LeftRing, dominators.as_slice()) {
+                (_, []) => None,
+                (false, [dominator, ..]) => Some(format!("dominated by {}", dominator.name)),
+                (true, [first, second, ..]) => Some(format!("dominated by {} and {}", first.name, second.name)),
+                (true, [dominator]) if dominator.can_be_worn_twice() => Some(format!("dominated by 2× {}", dominator.name)),
+                (true, [_]) => None,
+            }
+        })
+        .collect();
+
+    let mut kept = Vec::new();
+    let mut pruned = Vec::new();
+    for (item, verdict) in items.into_iter().zip(verdicts) {
+        match verdict {
+            Some(reason) => {
+                tracing::debug!("Pruned {}: {}", item.name, reason);
+                pruned.push(PrunedItem { item, reason });
+            },
+            None => kept.push(item),
+        }
+    }
+
+    (kept, pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::StatBound;
+
+    fn item(slot: ItemSlot, name: &str, stats: Stats) -> Item {
+        Item {
+            slot,
+            name: name.to_string(),
+            stats: Stats {
+                mind: 400,
+                ..stats
+            },
+            meld_slots: 2,
+            overmeldable: 1,
+        }
+    }
+
+    fn crit_det(critical: u32, determination: u32) -> Stats {
+        Stats {
+            critical,
+            determination,
+            ..Stats::default()
+        }
+    }
+
+    fn names(items: &[Item]) -> Vec<&str> {
+        items.iter().map(|item| item.name.as_str()).collect()
+    }
+
+    #[test]
+    fn dominated_item_removed() {
+        let items = vec![
+            item(ItemSlot::Head, "Worse", crit_det(200, 100)),
+            item(ItemSlot::Head, "Better", crit_det(250, 100)),
+        ];
+        let (kept, pruned) = prune_dominated(items, &Constraints::default());
+        assert_eq!(names(&kept), ["Better"]);
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].item.name, "Worse");
+        assert_eq!(pruned[0].reason, "dominated by Better");
+    }
+
+    #[test]
+    fn trade_offs_kept() {
+        // Neither is better on every substat, and items of other slots never compete
+        let items = vec![
+            item(ItemSlot::Head, "Crit", crit_det(250, 100)),
+            item(ItemSlot::Head, "Det", crit_det(100, 250)),
+            item(ItemSlot::Body, "Body", crit_det(0, 0)),
+        ];
+        let (kept, pruned) = prune_dominated(items, &Constraints::default());
+        assert_eq!(names(&kept), ["Crit", "Det", "Body"]);
+        assert!(pruned.is_empty());
+    }
+
+    #[test]
+    fn identical_items_keep_the_first() {
+        let items = vec![
+            item(ItemSlot::Head, "First", crit_det(200, 100)),
+            item(ItemSlot::Head, "Second", crit_det(200, 100)),
+        ];
+        let (kept, _) = prune_dominated(items, &Constraints::default());
+        assert_eq!(names(&kept), ["First"]);
+    }
+
+    #[test]
+    fn rings_need_two_dominators() {
+        let unique_ring = |name: &str, critical: u32| Item {
+            overmeldable: 0,
+            ..item(ItemSlot::LeftRing, name, crit_det(critical, 100))
+        };
+
+        // The worse ring is still the best choice for the other ring slot
+        let (kept, _) = prune_dominated(vec![unique_ring("Worse", 200), unique_ring("Better", 250)], &Constraints::default());
+        assert_eq!(names(&kept), ["Worse", "Better"]);
+
+        let (kept, _) = prune_dominated(vec![
+            unique_ring("Worse", 200),
+            unique_ring("Better", 250),
+            unique_ring("Best", 300),
+        ], &Constraints::default());
+        assert_eq!(names(&kept), ["Better", "Best"]);
+
+        // Unless the better ring can be worn twice
+        let crafted = item(ItemSlot::LeftRing, "Crafted", crit_det(250, 100));
+        let (kept, _) = prune_dominated(vec![unique_ring("Worse", 200), crafted], &Constraints::default());
+        assert_eq!(names(&kept), ["Crafted"]);
+    }
+
+    #[test]
+    fn constrained_stats_are_compared() {
+        let items = vec![
+            item(ItemSlot::Head, "Piety", Stats { piety: 100, ..crit_det(200, 100) }),
+            item(ItemSlot::Head, "Crit", crit_det(250, 100)),
+        ];
+        let (kept, _) = prune_dominated(items.clone(), &Constraints::default());
+        assert_eq!(names(&kept), ["Crit"]);
+
+        let mut constraints = Constraints::default();
+        constraints.stat_bounds.push(StatBound {
+            min: Some(500),
+            ..StatBound::new(Stat::Piety)
+        });
+        let (kept, _) = prune_dominated(items, &constraints);
+        assert_eq!(names(&kept), ["Piety", "Crit"]);
+    }
+}
@@ -0,0 +1,142 @@
+use itertools::Itertools;
+
+use crate::solver::Buff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuffKind {
+    Damage,
+    Critical,
+    DirectHit,
+}
+
+impl BuffKind {
+    pub fn buff(self, magnitude: f64) -> Buff {
+        match self {
+            BuffKind::Damage => Buff::Damage(magnitude),
+            BuffKind::Critical => Buff::Critical(magnitude),
+            BuffKind::DirectHit => Buff::DirectHit(magnitude),
+        }
+    }
+}
+
+impl std::fmt::Display for BuffKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuffKind::Damage => write!(f, "Damage"),
+            BuffKind::Critical => write!(f, "Critical"),
+            BuffKind::DirectHit => write!(f, "Direct hit"),
+        }
+    }
+}
+
+impl std::str::FromStr for BuffKind {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "damage" => Ok(Self::Damage),
+            "critical" => Ok(Self::Critical),
+            "direct_hit" => Ok(Self::DirectHit),
+            _ => Err(eyre::eyre!("Invalid value: {}, expected damage, critical or direct_hit", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    // On cooldown, held until the end of a short enough downtime it would overlap
+    Window,
+    // Right after the previous buff of the same job's sequence, cycling through it (BRD songs)
+    Sequence,
+}
+
+impl std::fmt::Display for Alignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alignment::Window => write!(f, "Raid buff window"),
+            Alignment::Sequence => write!(f, "Sequence"),
+        }
+    }
+}
+
+impl std::str::FromStr for Alignment {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "window" => Ok(Self::Window),
+            "sequence" => Ok(Self::Sequence),
+            _ => Err(eyre::eyre!("Invalid value: {}, expected window or sequence", s)),
+        }
+    }
+}
+
+// A raid buff of a party member, as described in raid_buffs.csv
+#[derive(Debug, Clone, PartialEq)]
+pub struct RaidBuff {
+    pub job: String,
+    pub name: String,
+    pub kind: BuffKind,
+    // 0.05 is 5% damage, or 5 points of crit/direct hit rate
+    pub magnitude: f64,
+    // Only for the first use, when it differs (BRD's Radiant Finale)
+    pub opener_magnitude: Option<f64>,
+    pub duration: f64,
+    // Unused by sequences
+    pub cooldown: f64,
+    // First use, in seconds after the pull
+    pub opener: f64,
+    pub alignment: Alignment,
+}
+
+impl RaidBuff {
+    pub fn buff(&self) -> Buff {
+        self.kind.buff(self.magnitude)
+    }
+
+    pub fn opener_buff(&self) -> Buff {
+        self.kind.buff(self.opener_magnitude.unwrap_or(self.magnitude))
+    }
+}
+
+impl TryFrom<csv::StringRecord> for RaidBuff {
+    type Error = eyre::Error;
+
+    fn try_from(record: csv::StringRecord) -> eyre::Result<Self> {
+        let field = |index: usize| record.get(index).unwrap_or_default().trim();
+        let number = |index: usize| -> eyre::Result<f64> {
+            field(index).parse().map_err(|error| eyre::eyre!("{}: {}", field(index), error))
+        };
+        let optional = |index: usize| -> eyre::Result<Option<f64>> {
+            if field(index).is_empty() { Ok(None) } else { number(index).map(Some) }
+        };
+
+        let raid_buff = Self {
+            job: field(0).to_string(),
+            name: field(1).to_string(),
+            kind: field(2).parse()?,
+            magnitude: number(3)?,
+            opener_magnitude: optional(4)?,
+            duration: number(5)?,
+            cooldown: optional(6)?.unwrap_or_default(),
+            opener: number(7)?,
+            alignment: field(8).parse()?,
+        };
+        // Both would schedule the buff forever
+        if raid_buff.duration <= 0.0 {
+            return Err(eyre::eyre!("{}: the duration must be positive", raid_buff.name));
+        }
+        if raid_buff.alignment == Alignment::Window && raid_buff.cooldown <= 0.0 {
+            return Err(eyre::eyre!("{}: the cooldown must be positive", raid_buff.name));
+        }
+        Ok(raid_buff)
+    }
+}
+
+// Jobs in the order they first appear
+pub fn raid_buff_jobs(raid_buffs: &[RaidBuff]) -> Vec<String> {
+    raid_buffs.iter()
+        .map(|raid_buff| raid_buff.job.clone())
+        .unique()
+        .collect()
+}
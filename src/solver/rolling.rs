@@ -1,28 +1,115 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use crate::data::*;
 use crate::ui::UiLink;
-use crate::solver::{Evaluator, EvaluatorWrapper, Solver, SAGE_BASE};
+use crate::solver::{complete_cancelled, meld_combinations, CancellationToken, CheckpointConfig, Checkpointer, Constraints, DiversityConfig, EquivalenceClasses, Evaluator, SlotAlternatives, Interruption, Solution, Solver, TopK, TopKExt, SAGE_BASE};
 
 use itertools::Itertools;
 
-#[derive(Clone)]
+// Stages after the item ones, which are numbered by how many slots are decided
+const MELD_X_STAGE: usize = ITEM_SLOTS.len();
+const MELD_IX_STAGE: usize = MELD_X_STAGE + 1;
+const FOOD_STAGE: usize = MELD_X_STAGE + 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlotOrder {
+    // Weapon, head, ..., rings
+    Fixed,
+    // Next slot is the one where the choice of item makes the most difference
+    Adaptive,
+}
+
+#[derive(Debug, Clone)]
 pub struct RollingConfig {
     pub rolling_k: usize,
+    pub slot_order: SlotOrder,
+    // Score partial gearsets as if the empty slots had the best stats available for them
+    pub optimistic_bound: bool,
 }
 
 impl Default for RollingConfig {
     fn default() -> Self {
         Self {
             rolling_k: 1024,
+            slot_order: SlotOrder::Adaptive,
+            optimistic_bound: true,
         }
     }
 }
 
+// Fills the empty slots of a partial gearset with a made-up item having, for each stat, the best
+// value of all the items for that slot before evaluating it. This is an optimistic completion: the
+// gearsets kept after each slot are the ones that could still end up the best, instead of the ones
+// that are the best with half of their gear missing.
+struct OptimisticEvaluator {
+    evaluator: Arc<dyn Evaluator + Send+Sync>,
+    completion: Vec<Item>,
+}
+
+impl OptimisticEvaluator {
+    fn new(evaluator: Arc<dyn Evaluator + Send+Sync>, items: &[Vec<Item>]) -> Self {
+        let completion = ITEM_SLOTS.iter().zip(items)
+            .map(|(slot, items)| items.iter().fold(
+                Item {
+                    slot: *slot,
+                    name: format!("(best possible {})", slot),
+                    ..Default::default()
+                },
+                |mut best, item| {
+                    let stats = &mut best.stats;
+                    stats.weapon_damage = stats.weapon_damage.max(item.stats.weapon_damage);
+                    stats.mind = stats.mind.max(item.stats.mind);
+                    stats.vitality = stats.vitality.max(item.stats.vitality);
+                    stats.piety = stats.piety.max(item.stats.piety);
+                    stats.direct_hit = stats.direct_hit.max(item.stats.direct_hit);
+                    stats.critical = stats.critical.max(item.stats.critical);
+                    stats.determination = stats.determination.max(item.stats.determination);
+                    stats.spell_speed = stats.spell_speed.max(item.stats.spell_speed);
+                    best.meld_slots = best.meld_slots.max(item.meld_slots);
+                    best.overmeldable = best.overmeldable.max(item.overmeldable);
+                    best
+                }
+            ))
+            .collect();
+
+        Self {
+            evaluator,
+            completion,
+        }
+    }
+
+    fn complete(&self, gearset: &Gearset) -> Gearset {
+        let mut gearset = gearset.clone();
+        gearset.items.iter_mut()
+            .zip(self.completion.iter())
+            .filter(|(item, _)| item.name.is_empty())
+            .for_each(|(item, best)| *item = best.clone());
+        gearset
+    }
+}
+
+impl Evaluator for OptimisticEvaluator {
+    fn stats_dps(&self, stats: &Stats) -> f64 {
+        self.evaluator.stats_dps(stats)
+    }
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.evaluator.dps(&self.complete(gearset))
+    }
+
+    fn settings(&self) -> String {
+        format!("Optimistic {}", self.evaluator.settings())
+    }
+}
+
 pub struct RollingSolver {
     items: Vec<Item>,
     ui_link: UiLink,
     evaluator: Arc<dyn Evaluator + Send+Sync>,
     config: RollingConfig,
+    constraints: Constraints,
+    diversity: DiversityConfig,
+    checkpoint: CheckpointConfig,
 }
 
 impl RollingSolver {
@@ -32,6 +119,9 @@ impl RollingSolver {
             ui_link,
             evaluator,
             config: RollingConfig::default(),
+            constraints: Constraints::default(),
+            diversity: DiversityConfig::default(),
+            checkpoint: CheckpointConfig::default(),
         }
     }
 
@@ -48,11 +138,31 @@ impl RollingSolver {
             ..self
         }
     }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    pub fn with_diversity(self, diversity: DiversityConfig) -> Self {
+        Self {
+            diversity,
+            ..self
+        }
+    }
+
+    pub fn with_checkpoint(self, checkpoint: CheckpointConfig) -> Self {
+        Self {
+            checkpoint,
+            ..self
+        }
+    }
 }
 impl Solver for RollingSolver {
-    fn solve(&self) -> eyre::Result<Vec<Gearset>> {
-        self.ui_link.set_count(0)?;
-        self.ui_link.message("Loading items...")?;
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        self.ui_link.stage("Loading items...", 0)?;
         let items = self.items.clone();
         let (weapon, items): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| item.slot == ItemSlot::Weapon);
         let (head, items): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| item.slot == ItemSlot::Head);
@@ -82,105 +192,259 @@ impl Solver for RollingSolver {
             .unzip();
 
         let items = vec![
-            weapon.into_iter(),
-            head.into_iter(),
-            torso.into_iter(),
-            hands.into_iter(),
-            legs.into_iter(),
-            feet.into_iter(),
-            ear.into_iter(),
-            neck.into_iter(),
-            bracelet.into_iter(),
-            left_ring.into_iter(),
-            right_ring.into_iter(),
+            weapon,
+            head,
+            torso,
+            hands,
+            legs,
+            feet,
+            ear,
+            neck,
+            bracelet,
+            left_ring,
+            right_ring,
         ];
 
-        self.ui_link.message("Ranking gear...")?;
-
-        let base_gearset = Gearset {
-            base: SAGE_BASE,
-            ..Default::default()
+        let partial_evaluator: Arc<dyn Evaluator + Send+Sync> = if self.config.optimistic_bound {
+            Arc::new(OptimisticEvaluator::new(self.evaluator.clone(), &items))
+        } else {
+            self.evaluator.clone()
         };
-        let mut gearsets = vec![base_gearset];
-        for item_list in items {
-            gearsets = item_list
-                .cartesian_product(gearsets.into_iter())
-                .map(|(item, mut gearset)| {
-                    gearset.items[item.slot as usize] = item.clone();
-                    gearset
-                })
-                .filter(|gearset| {
-                    gearset.is_valid()
-                })
-                .inspect(|_| self.ui_link.increment().unwrap())
-                .map(|gearset| EvaluatorWrapper { evaluator: self.evaluator.clone(), gearset })
-                .map(std::cmp::Reverse)
-                .k_smallest(self.config.rolling_k)
-                .dedup()
-                .map(|rev| rev.0)
-                .map(|EvaluatorWrapper { gearset, .. }| gearset)
+
+        let checkpointer = Checkpointer::new(
+            self.checkpoint.clone(),
+            &self.items,
+            &*self.evaluator,
+            (&self.config, &self.constraints, &self.diversity),
+        );
+        let (first_stage, mut gearsets) = checkpointer.start().unwrap_or_else(|| {
+            let base_gearset = Gearset {
+                base: SAGE_BASE,
+                ..Default::default()
+            };
+            (0, vec![base_gearset])
+        });
+        // Slots are decided one by one, so the ones still empty are the same in every gearset
+        let mut remaining: Vec<_> = ITEM_SLOTS.iter()
+            .copied()
+            .filter(|slot| gearsets[0].items[*slot as usize].name.is_empty())
+            .collect();
+        let mut slot_alternatives = SlotAlternatives::default();
+        while !remaining.is_empty() {
+            let stage = ITEM_SLOTS.len() - remaining.len();
+            let slot = match self.config.slot_order {
+                SlotOrder::Fixed => remaining[0],
+                SlotOrder::Adaptive => most_discriminating_slot(partial_evaluator.as_ref(), &gearsets[0], &remaining, &items),
+            };
+            remaining.retain(|remaining_slot| *remaining_slot != slot);
+            let item_list = &items[slot as usize];
+
+            self.ui_link.stage(format!("Ranking gear: {}...", slot), item_list.len() * gearsets.len())?;
+            let progress = self.ui_link.progress();
+            let (cursor, best) = checkpointer.begin_stage(stage);
+            let interruption = Interruption::new(token);
+            let mut classes = EquivalenceClasses::new(&self.constraints);
+            let ranked = best.into_iter()
+                .chain(item_list.iter()
+                    .cartesian_product(gearsets.iter())
+                    .skip(cursor)
+                    .map(|(item, gearset)| {
+                        let mut gearset = gearset.clone();
+                        gearset.items[item.slot as usize] = item.clone();
+                        gearset
+                    })
+                    .take_while(|_| interruption.proceed())
+                    .inspect(|_| {
+                        progress.tick();
+                        checkpointer.advance();
+                    })
+                    .filter(|gearset| {
+                        gearset.is_valid() && self.constraints.reachable(gearset, &food)
+                    })
+                )
+                .filter(|gearset| classes.first_of_class(gearset))
+                .top_k(TopK::new(self.config.rolling_k, partial_evaluator.clone())
+                    .checkpoint_to(&checkpointer, stage, &gearsets)
+                );
+
+            if interruption.happened() {
+                checkpointer.save(stage, &gearsets, &ranked);
+                // Slots after this one are still empty, nothing is complete yet
+                return Ok(Solution::new(Vec::new(), true));
+            }
+            gearsets = ranked;
+            let decided = ITEM_SLOTS.iter()
+                .copied()
+                .filter(|slot| !remaining.contains(slot))
                 .collect();
-        }
+            classes.set_aside(&gearsets, decided, &mut slot_alternatives);
 
-        self.ui_link.set_count(0)?;
-        self.ui_link.message("Ranking food/melds...")?;
-
-        let gearsets: Vec<_> = gearsets.into_iter()
-            .flat_map(|gearset| {
-                let (possible_melds_x, _) = gearset.possible_melds();
-                let (meld_slots_x, _) = gearset.meld_slots();
-                let tentative_meld_x = possible_melds_x.into_iter()
-                    .map(|materia_count| (0..=materia_count))
-                    .multi_cartesian_product()
-                    .filter(move |meld| meld.iter().sum::<u32>() == meld_slots_x);
-                std::iter::once(gearset).cartesian_product(tentative_meld_x)
-            })
-            .map(|(mut gearset, meld_x)| {
-                gearset.meld_x = meld_x.try_into().unwrap();
-                gearset
-            })
-            .inspect(|_| self.ui_link.increment().unwrap())
-            .map(|gearset| EvaluatorWrapper { evaluator: self.evaluator.clone(), gearset })
-            .map(std::cmp::Reverse)
-            .k_smallest(self.config.rolling_k)
-            .map(|rev| rev.0)
-            .map(|EvaluatorWrapper { gearset, .. }| gearset)
-            .flat_map(|gearset| {
-                let (_, possible_melds_ix) = gearset.possible_melds();
-                let (_, meld_slots_ix) = gearset.meld_slots();
-                let tentative_meld_ix = possible_melds_ix.into_iter()
-                    .map(|materia_count| (0..=materia_count))
-                    .multi_cartesian_product()
-                    .filter(move |meld| meld.iter().sum::<u32>() == meld_slots_ix);
-                std::iter::once(gearset).cartesian_product(tentative_meld_ix)
-            })
-            .map(|(mut gearset, meld_ix)| {
-                gearset.meld_ix = meld_ix.try_into().unwrap();
-                gearset
-            })
-            .inspect(|_| self.ui_link.increment().unwrap())
-            .map(|gearset| EvaluatorWrapper { evaluator: self.evaluator.clone(), gearset })
-            .map(std::cmp::Reverse)
-            .k_smallest(self.config.rolling_k)
-            .map(|rev| rev.0)
-            .map(|EvaluatorWrapper { gearset, .. }| gearset)
-            .cartesian_product(food.into_iter())
-            .map(|(mut gearset, food)| {
-                gearset.food = food;
-                gearset
-            })
-            .inspect(|_| self.ui_link.increment().unwrap())
-            .map(|gearset| EvaluatorWrapper { evaluator: self.evaluator.clone(), gearset })
-            .map(std::cmp::Reverse)
-            .k_smallest(self.config.rolling_k)
-            .map(|rev| rev.0)
-            .map(|EvaluatorWrapper { gearset, .. }| gearset)
+            if gearsets.is_empty() {
+                return Ok(Solution::new(gearsets, false));
+            }
+        }
+        let alternatives: HashMap<_, _> = gearsets.iter()
+            .map(|gearset| (gearset.items.clone(), slot_alternatives.of(&gearset.items)))
+            .filter(|(_, alternatives)| !alternatives.is_empty())
             .collect();
 
-        Ok(gearsets)
+        if first_stage <= MELD_X_STAGE {
+            let total = gearsets.iter()
+                .map(|gearset| meld_combinations(&gearset.possible_melds().0, gearset.meld_slots().0))
+                .sum();
+            self.ui_link.stage("Ranking melds (X)...", total)?;
+            let progress = self.ui_link.progress();
+            let (cursor, best) = checkpointer.begin_stage(MELD_X_STAGE);
+            let interruption = Interruption::new(token);
+
+            let melded_x: Vec<_> = best.into_iter()
+                .chain(gearsets.iter()
+                    .flat_map(|gearset| {
+                        let (possible_melds_x, _) = gearset.possible_melds();
+                        let (meld_slots_x, _) = gearset.meld_slots();
+                        let tentative_meld_x = possible_melds_x.into_iter()
+                            .map(|materia_count| 0..=materia_count)
+                            .multi_cartesian_product()
+                            .filter(move |meld| meld.iter().sum::<u32>() == meld_slots_x);
+                        std::iter::once(gearset).cartesian_product(tentative_meld_x)
+                    })
+                    .skip(cursor)
+                    .map(|(gearset, meld_x)| {
+                        let mut gearset = gearset.clone();
+                        gearset.meld_x = meld_x.try_into().unwrap();
+                        gearset
+                    })
+                    .take_while(|_| interruption.proceed())
+                    .inspect(|_| {
+                        progress.tick();
+                        checkpointer.advance();
+                    })
+                    .filter(|gearset| self.constraints.reachable(gearset, &food))
+                )
+                .top_k(TopK::new(self.config.rolling_k, self.evaluator.clone())
+                    .checkpoint_to(&checkpointer, MELD_X_STAGE, &gearsets)
+                );
+
+            if interruption.happened() {
+                checkpointer.save(MELD_X_STAGE, &gearsets, &melded_x);
+                return Ok(Solution::new(reattach_alternatives(complete_cancelled(melded_x, gearsets, &food, self.evaluator.as_ref(), &self.constraints), &alternatives), true));
+            }
+            gearsets = melded_x;
+        }
+
+        if first_stage <= MELD_IX_STAGE {
+            let total = gearsets.iter()
+                .map(|gearset| meld_combinations(&gearset.possible_melds().1, gearset.meld_slots().1))
+                .sum();
+            self.ui_link.stage("Ranking melds (IX)...", total)?;
+            let progress = self.ui_link.progress();
+            let (cursor, best) = checkpointer.begin_stage(MELD_IX_STAGE);
+            let interruption = Interruption::new(token);
+
+            let melded_ix: Vec<_> = best.into_iter()
+                .chain(gearsets.iter()
+                    .flat_map(|gearset| {
+                        let (_, possible_melds_ix) = gearset.possible_melds();
+                        let (_, meld_slots_ix) = gearset.meld_slots();
+                        let tentative_meld_ix = possible_melds_ix.into_iter()
+                            .map(|materia_count| 0..=materia_count)
+                            .multi_cartesian_product()
+                            .filter(move |meld| meld.iter().sum::<u32>() == meld_slots_ix);
+                        std::iter::once(gearset).cartesian_product(tentative_meld_ix)
+                    })
+                    .skip(cursor)
+                    .map(|(gearset, meld_ix)| {
+                        let mut gearset = gearset.clone();
+                        gearset.meld_ix = meld_ix.try_into().unwrap();
+                        gearset
+                    })
+                    .take_while(|_| interruption.proceed())
+                    .inspect(|_| {
+                        progress.tick();
+                        checkpointer.advance();
+                    })
+                    .filter(|gearset| self.constraints.reachable(gearset, &food))
+                )
+                .top_k(TopK::new(self.config.rolling_k, self.evaluator.clone())
+                    .checkpoint_to(&checkpointer, MELD_IX_STAGE, &gearsets)
+                );
+
+            if interruption.happened() {
+                checkpointer.save(MELD_IX_STAGE, &gearsets, &melded_ix);
+                return Ok(Solution::new(reattach_alternatives(complete_cancelled(melded_ix, gearsets, &food, self.evaluator.as_ref(), &self.constraints), &alternatives), true));
+            }
+            gearsets = melded_ix;
+        }
+
+        self.ui_link.stage("Ranking food...", gearsets.len() * food.len())?;
+        let progress = self.ui_link.progress();
+        let (cursor, best) = checkpointer.begin_stage(FOOD_STAGE);
+        let interruption = Interruption::new(token);
+
+        let fed: Vec<_> = best.into_iter()
+            .chain(gearsets.iter()
+                .cartesian_product(food.iter())
+                .skip(cursor)
+                .map(|(gearset, food)| {
+                    let mut gearset = gearset.clone();
+                    gearset.food = food.clone();
+                    gearset
+                })
+                .take_while(|_| interruption.proceed())
+                .inspect(|_| {
+                    progress.tick();
+                    checkpointer.advance();
+                })
+                .filter(|gearset| self.constraints.accepts(gearset))
+            )
+            // Only on the last stage: the best melds and food of an item set are only known now
+            .top_k(TopK::new(self.config.rolling_k, self.evaluator.clone())
+                .diverse(self.diversity.min_item_swaps)
+                .publish_to(&self.ui_link)
+                .checkpoint_to(&checkpointer, FOOD_STAGE, &gearsets)
+            );
+
+        if interruption.happened() {
+            checkpointer.save(FOOD_STAGE, &gearsets, &fed);
+            return Ok(Solution::new(reattach_alternatives(complete_cancelled(fed, gearsets, &food, self.evaluator.as_ref(), &self.constraints), &alternatives), true));
+        }
+        checkpointer.finish();
+        Ok(Solution::new(reattach_alternatives(fed, &alternatives), false))
     }
 
     fn dps(&self, gearset: &Gearset) -> f64 {
         self.evaluator.dps(gearset)
     }
 }
+
+// Melds and food don't change the items, so the alternatives stay aside until the very end
+fn reattach_alternatives(mut gearsets: Vec<Gearset>, alternatives: &HashMap<[Item; 11], Vec<[Item; 11]>>) -> Vec<Gearset> {
+    for gearset in gearsets.iter_mut() {
+        if let Some(alternatives) = alternatives.get(&gearset.items) {
+            gearset.alternatives = alternatives.clone();
+        }
+    }
+    gearsets
+}
+
+// The slot where the item choice matters the most, measured on the current best partial gearset as
+// the DPS spread between its best and worst item. Deciding it first means the K gearsets kept
+// afterwards are spent on the choices that are actually close.
+fn most_discriminating_slot(evaluator: &dyn Evaluator, gearset: &Gearset, remaining: &[ItemSlot], items: &[Vec<Item>]) -> ItemSlot {
+    remaining.iter()
+        .map(|slot| {
+            let (min, max) = items[*slot as usize].iter()
+                .map(|item| {
+                    let mut candidate = gearset.clone();
+                    candidate.items[*slot as usize] = item.clone();
+                    candidate
+                })
+                .filter(Gearset::is_valid)
+                .map(|candidate| evaluator.dps(&candidate))
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), dps| (min.min(dps), max.max(dps)));
+            (*slot, max - min)
+        })
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .map(|(slot, _)| slot)
+        .unwrap()
+}
@@ -3,9 +3,9 @@ use itertools::Itertools;
 use std::sync::Arc;
 
 use crate::data::*;
-use crate::solver::{Evaluator, EvaluatorWrapper, Solver, SAGE_BASE};
+use crate::solver::{complete_cancelled, meld_combinations, CancellationToken, CheckpointConfig, Checkpointer, Constraints, DiversityConfig, Evaluator, Interruption, Solution, Solver, TopK, TopKExt, SAGE_BASE};
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct SplitConfig {
     pub k_stage_1: usize,
     pub k_stage_2: usize,
@@ -25,8 +25,15 @@ pub struct SplitSolver {
     ui_link: UiLink,
     evaluator: Arc<dyn Evaluator + Send+Sync>,
     config: SplitConfig,
+    constraints: Constraints,
+    diversity: DiversityConfig,
+    checkpoint: CheckpointConfig,
 }
 
+// Gear, then food and melds
+const GEAR_STAGE: usize = 0;
+const MELD_STAGE: usize = 1;
+
 impl SplitSolver {
     pub fn new(ui_link: UiLink, evaluator: Arc<dyn Evaluator + Send+Sync>) -> Self {
         Self {
@@ -34,6 +41,9 @@ impl SplitSolver {
             ui_link,
             evaluator,
             config: SplitConfig::default(),
+            constraints: Constraints::default(),
+            diversity: DiversityConfig::default(),
+            checkpoint: CheckpointConfig::default(),
         }
     }
 
@@ -50,12 +60,32 @@ impl SplitSolver {
             ..self
         }
     }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    pub fn with_diversity(self, diversity: DiversityConfig) -> Self {
+        Self {
+            diversity,
+            ..self
+        }
+    }
+
+    pub fn with_checkpoint(self, checkpoint: CheckpointConfig) -> Self {
+        Self {
+            checkpoint,
+            ..self
+        }
+    }
 }
 
 impl Solver for SplitSolver {
-    fn solve(&self) -> eyre::Result<Vec<Gearset>> {
-        self.ui_link.set_count(0)?;
-        self.ui_link.message("Loading items...")?;
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        self.ui_link.stage("Loading items...", 0)?;
         let items = self.items.clone();
         let (arme, items): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| item.slot == ItemSlot::Weapon);
         let (tête, items): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| item.slot == ItemSlot::Head);
@@ -79,82 +109,137 @@ impl Solver for SplitSolver {
             .combinations(2)
             .collect();
 
+        let total = [&arme, &tête, &torse, &mains, &jambes, &pieds, &oreille, &collier, &bracelet].into_iter()
+            .map(Vec::len)
+            .product::<usize>() * bagues.len();
+
+        // Borrowed until the combinations a checkpoint skips are behind
         let product = vec![
-            arme.into_iter(),
-            tête.into_iter(),
-            torse.into_iter(),
-            mains.into_iter(),
-            jambes.into_iter(),
-            pieds.into_iter(),
-            oreille.into_iter(),
-            collier.into_iter(),
-            bracelet.into_iter(),
+            arme.iter(),
+            tête.iter(),
+            torse.iter(),
+            mains.iter(),
+            jambes.iter(),
+            pieds.iter(),
+            oreille.iter(),
+            collier.iter(),
+            bracelet.iter(),
         ].into_iter()
             .multi_cartesian_product()
-            .cartesian_product(bagues)
-            .map(|(items, rings)| items.into_iter().chain(rings.into_iter()).collect::<Vec<_>>());
-
-        self.ui_link.message("Ranking gear...")?;
-
-        let results = product
-            .map(|items| {
-                let mut gearset = Gearset::from_items(items);
-                gearset.base = SAGE_BASE;
-                gearset
-            })
-            .filter(|gearset| {
-                gearset.is_valid()
-            })
-            .inspect(|_| self.ui_link.increment().unwrap())
-            .map(|gearset| EvaluatorWrapper { evaluator: self.evaluator.clone(), gearset })
-            .map(std::cmp::Reverse)
-            .k_smallest(self.config.k_stage_1)
-            .map(|rev| rev.0)
-            .map(|EvaluatorWrapper { gearset, .. }| gearset);
-
-        self.ui_link.set_count(0)?;
-        self.ui_link.message("Ranking food/melds...")?;
-
-        let gearsets: Vec<_> = results.into_iter()
-            .flat_map(|gearset| {
+            .cartesian_product(bagues.iter());
+
+        let checkpointer = Checkpointer::new(
+            self.checkpoint.clone(),
+            &self.items,
+            &*self.evaluator,
+            (&self.config, &self.constraints, &self.diversity),
+        );
+        // The gear stage has no input, the meld stage's input is what the gear stage found
+        let results = match checkpointer.start() {
+            Some((MELD_STAGE, results)) => results,
+            _ => {
+                self.ui_link.stage("Ranking gear...", total)?;
+                let progress = self.ui_link.progress();
+                let (cursor, best) = checkpointer.begin_stage(GEAR_STAGE);
+                let interruption = Interruption::new(token);
+
+                let results = best.into_iter()
+                    .chain(product
+                        .skip(cursor)
+                        .map(|(items, rings)| {
+                            let mut gearset = Gearset::from_items(items.into_iter().chain(rings).cloned().collect());
+                            gearset.base = SAGE_BASE;
+                            gearset
+                        })
+                        .take_while(|_| interruption.proceed())
+                        .inspect(|_| {
+                            progress.tick();
+                            checkpointer.advance();
+                        })
+                        .filter(|gearset| {
+                            gearset.is_valid() && self.constraints.reachable(gearset, &nourriture)
+                        })
+                    )
+                    .top_k(TopK::new(self.config.k_stage_1, self.evaluator.clone())
+                        .checkpoint_to(&checkpointer, GEAR_STAGE, &[])
+                    );
+
+                if interruption.happened() {
+                    checkpointer.save(GEAR_STAGE, &[], &results);
+                    // Nothing has its melds and food yet
+                    return Ok(Solution::new(Vec::new(), true));
+                }
+                results
+            },
+        };
+
+        let total = results.iter()
+            .map(|gearset| {
                 let (possible_melds_x, possible_melds_ix) = gearset.possible_melds();
                 let (meld_slots_x, meld_slots_ix) = gearset.meld_slots();
-                tracing::debug!("{:?}", gearset.items);
-                tracing::debug!("possible: {:?}, {:?}", possible_melds_x, possible_melds_ix);
-                tracing::debug!("slots: {:?}, {:?}", meld_slots_x, meld_slots_ix);
-
-                let tentative_meld_x: Vec<_> = possible_melds_x.into_iter()
-                    .map(|materia_count| (0..=materia_count))
-                    .multi_cartesian_product()
-                    .filter(|meld| meld.iter().sum::<u32>() == meld_slots_x)
-                    .collect();
-                let tentative_meld_ix: Vec<_> = possible_melds_ix.into_iter()
-                    .map(|materia_count| (0..=materia_count))
-                    .multi_cartesian_product()
-                    .filter(|meld| meld.iter().sum::<u32>() == meld_slots_ix)
-                    .collect();
-
-                tracing::debug!("possible melds X: {}, IX: {}", tentative_meld_x.len(), tentative_meld_ix.len());
-
-                std::iter::once(gearset).cartesian_product(nourriture.iter()).cartesian_product(tentative_meld_x.into_iter()).cartesian_product(tentative_meld_ix.into_iter())
-            })
-            .map(|(((gearset, food), meld_x), meld_ix)| (gearset, food, meld_x, meld_ix))
-            .map(|(mut gearset, food, meld_x, meld_ix)| {
-                gearset.food = food.clone();
-                gearset.meld_x = meld_x.try_into().unwrap();
-                gearset.meld_ix = meld_ix.try_into().unwrap();
-                gearset
+                nourriture.len()
+                    * meld_combinations(&possible_melds_x, meld_slots_x)
+                    * meld_combinations(&possible_melds_ix, meld_slots_ix)
             })
-            .inspect(|_| self.ui_link.increment().unwrap())
-            .map(|gearset| EvaluatorWrapper { evaluator: self.evaluator.clone(), gearset })
-            .map(std::cmp::Reverse)
-            .k_smallest(self.config.k_stage_2)
-            .map(|rev| rev.0)
-            .map(|EvaluatorWrapper { gearset, .. }| gearset)
-            .collect();
-
-
-        Ok(gearsets)
+            .sum();
+        self.ui_link.stage("Ranking food/melds...", total)?;
+        let progress = self.ui_link.progress();
+        let (cursor, best) = checkpointer.begin_stage(MELD_STAGE);
+        let interruption = Interruption::new(token);
+
+        let gearsets: Vec<_> = best.into_iter()
+            .chain(results.iter()
+                .flat_map(|gearset| {
+                    let (possible_melds_x, possible_melds_ix) = gearset.possible_melds();
+                    let (meld_slots_x, meld_slots_ix) = gearset.meld_slots();
+                    tracing::debug!("{:?}", gearset.items);
+                    tracing::debug!("possible: {:?}, {:?}", possible_melds_x, possible_melds_ix);
+                    tracing::debug!("slots: {:?}, {:?}", meld_slots_x, meld_slots_ix);
+
+                    let tentative_meld_x: Vec<_> = possible_melds_x.into_iter()
+                        .map(|materia_count| 0..=materia_count)
+                        .multi_cartesian_product()
+                        .filter(|meld| meld.iter().sum::<u32>() == meld_slots_x)
+                        .collect();
+                    let tentative_meld_ix: Vec<_> = possible_melds_ix.into_iter()
+                        .map(|materia_count| 0..=materia_count)
+                        .multi_cartesian_product()
+                        .filter(|meld| meld.iter().sum::<u32>() == meld_slots_ix)
+                        .collect();
+
+                    tracing::debug!("possible melds X: {}, IX: {}", tentative_meld_x.len(), tentative_meld_ix.len());
+
+                    std::iter::once(gearset).cartesian_product(nourriture.iter()).cartesian_product(tentative_meld_x).cartesian_product(tentative_meld_ix)
+                })
+                .skip(cursor)
+                .map(|(((gearset, food), meld_x), meld_ix)| (gearset, food, meld_x, meld_ix))
+                .map(|(gearset, food, meld_x, meld_ix)| {
+                    let mut gearset = gearset.clone();
+                    gearset.food = food.clone();
+                    gearset.meld_x = meld_x.try_into().unwrap();
+                    gearset.meld_ix = meld_ix.try_into().unwrap();
+                    gearset
+                })
+                .take_while(|_| interruption.proceed())
+                .inspect(|_| {
+                    progress.tick();
+                    checkpointer.advance();
+                })
+                .filter(|gearset| self.constraints.accepts(gearset))
+            )
+            // Only on the last stage: the best melds and food of an item set are only known now
+            .top_k(TopK::new(self.config.k_stage_2, self.evaluator.clone())
+                .diverse(self.diversity.min_item_swaps)
+                .publish_to(&self.ui_link)
+                .checkpoint_to(&checkpointer, MELD_STAGE, &results)
+            );
+
+        if interruption.happened() {
+            checkpointer.save(MELD_STAGE, &results, &gearsets);
+            return Ok(Solution::new(complete_cancelled(gearsets, results, &nourriture, self.evaluator.as_ref(), &self.constraints), true));
+        }
+        checkpointer.finish();
+        Ok(Solution::new(gearsets, false))
     }
 
     fn dps(&self, gearset: &Gearset) -> f64 {
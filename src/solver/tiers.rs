@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use crate::data::*;
+use crate::ui::UiLink;
+use crate::solver::{gcd_tiers, CancellationToken, Constraints, Evaluator, GcdConstraint, Solution, Solver, SolverBuilder, SAGE_BASE};
+
+// Runs another solver once per GCD tier and keeps the best gearset of each tier, fastest first.
+// Tiers no gearset can reach just don't show up in the results.
+pub struct GcdTierSolver {
+    items: Vec<Item>,
+    ui_link: UiLink,
+    evaluator: Arc<dyn Evaluator + Send+Sync>,
+    constraints: Constraints,
+    solver: Box<SolverBuilder>,
+}
+
+impl GcdTierSolver {
+    pub fn new(
+        ui_link: UiLink,
+        evaluator: Arc<dyn Evaluator + Send+Sync>,
+        solver: impl Fn(Vec<Item>, Constraints) -> Arc<dyn Solver + Send+Sync> + Send+Sync + 'static,
+    ) -> Self {
+        Self {
+            items: Vec::default(),
+            ui_link,
+            evaluator,
+            constraints: Constraints::default(),
+            solver: Box::new(solver),
+        }
+    }
+
+    pub fn with_items(self, items: Vec<Item>) -> Self {
+        Self {
+            items,
+            ..self
+        }
+    }
+
+    pub fn with_constraints(self, constraints: Constraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+}
+
+impl Solver for GcdTierSolver {
+    fn solve(&self, token: &CancellationToken) -> eyre::Result<Solution> {
+        // A GCD constraint narrows down the tiers instead of being replaced by them
+        let tiers: Vec<_> = gcd_tiers(&self.items, &SAGE_BASE).into_iter()
+            .filter(|tier| match self.constraints.gcd {
+                None => true,
+                Some(GcdConstraint::Equal) => *tier == self.constraints.gcd_target,
+                Some(GcdConstraint::AtMost) => *tier <= self.constraints.gcd_target,
+            })
+            .collect();
+
+        let mut gearsets = Vec::new();
+        let mut partial = false;
+        for (index, tier) in tiers.iter().enumerate() {
+            if token.is_cancelled() {
+                partial = true;
+                break;
+            }
+            tracing::info!("Solving GCD tier {:.2} ({}/{})", *tier as f64 / 100.0, index + 1, tiers.len());
+            let constraints = self.constraints.clone().with_gcd_tier(*tier);
+            let solution = (self.solver)(self.items.clone(), constraints).solve(token)?;
+            // A cancelled tier might not have reached its melds yet
+            if solution.partial {
+                partial = true;
+                break;
+            }
+            gearsets.extend(solution.gearsets.into_iter().next());
+            // The inner solver only showed its own tier
+            self.ui_link.provisional_gearsets(gearsets.iter()
+                .map(|gearset| (gearset.clone(), self.evaluator.dps(gearset)))
+                .collect()
+            )?;
+        }
+
+        Ok(Solution::new(gearsets, partial))
+    }
+
+    fn dps(&self, gearset: &Gearset) -> f64 {
+        self.evaluator.dps(gearset)
+    }
+}
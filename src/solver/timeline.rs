@@ -1,13 +1,76 @@
 use std::collections::HashSet;
-use std::sync::Mutex;
+use std::sync::Arc;
 use std::collections::HashMap;
 
 use itertools::Itertools;
 
 use crate::data::*;
+use crate::damage::{chances, expected_damage, Action, Buffs};
+use crate::utils::Unit;
+use crate::solver::{raid_buff_jobs, Alignment, FightKey, RaidBuff, TimelineCache};
 
 const PHLEGMA_CD: f64 = 40.0;
-const PHLEGMA_POTENCY: f64 = 600.0;
+const PHLEGMA_CHARGES: u32 = 2;
+const DOSIS: Action = Action::direct(330);
+const PHLEGMA: Action = Action::direct(600);
+const EDOSIS_TICK: Action = Action::over_time(70);
+const EDOSIS_DURATION: f64 = 30.0;
+// DoTs tick on a server clock, every 3s, whenever they were applied
+const SERVER_TICK: f64 = 3.0;
+
+// All off by default, which gives the same timeline as before these settings existed
+#[derive(Debug, Clone, Default)]
+pub struct DowntimeConfig {
+    // Cast a dosis before the boss is back, landing right as it becomes targetable
+    pub precast: bool,
+    // Allow refreshing edosis right before going untargetable, letting it tick again after short
+    // downtime instead of refreshing as soon as the boss is back
+    pub refresh_before: bool,
+    // Raid buffs that would overlap a downtime up to this long are held until it ends. Without
+    // it, a buff that would end in downtime is pushed back by its duration.
+    pub hold_buffs: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TickPhase {
+    // Seconds after the pull of the first server tick, within the 3s period
+    Fixed(f64),
+    // Unknown: the timeline evaluator averages over every phase, the Monte Carlo evaluator rolls one
+    // per run
+    Random,
+}
+
+impl std::fmt::Display for TickPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TickPhase::Fixed(phase) => write!(f, "Fixed ({}s)", phase),
+            TickPhase::Random => write!(f, "Random"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DotBuffs {
+    // Buffs up when the DoT is applied count for all its ticks, like the game does
+    Snapshot,
+    // Each tick gets the buffs up when it lands
+    PerTick,
+}
+
+#[derive(Debug, Clone)]
+pub struct DotConfig {
+    pub tick_phase: TickPhase,
+    pub buffs: DotBuffs,
+}
+
+impl Default for DotConfig {
+    fn default() -> Self {
+        Self {
+            tick_phase: TickPhase::Fixed(1.5),
+            buffs: DotBuffs::Snapshot,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct TimespanSearch<T: Clone> {
@@ -54,15 +117,6 @@ impl<T: Clone> TimespanSearch<T> {
             .map(|index| &self.data[**index])
             .collect()
     }
-
-    // Doesn't take into account overlapping spans, but it's all right, this only really matters
-    // for downtime anyway (and downtime doesn't overlap)
-    pub fn next_start(&self, instant: f64) -> Option<&(Timespan, T)> {
-        // Array is sorted so we can use next() after a filter to get the first next downtime
-        self.begins.iter()
-            .find(|index| self.data[**index].0.begin >= instant)
-            .and_then(|index| self.data.get(*index))
-    }
 }
 
 impl<T: Clone> From<Vec<(Timespan, T)>> for TimespanSearch<T> {
@@ -93,16 +147,20 @@ pub struct Timeline {
     buffs: TimespanSearch<Buff>,
     comp_mind: f64,
     end: f64,
-    timeline_cache: Mutex<HashMap<usize, Vec<BuffedTimelineAction>>>,
+    dot: DotConfig,
+    downtime_config: DowntimeConfig,
+    timeline_cache: Arc<TimelineCache>,
 }
 
-// TODO Add tweaking for downtimes to take into account players using 1 GCD after downtime, some
-// buffs are also used before the end of downtime (potion, something on NIN, and BRD's songs)
+// TODO some buffs are also used before the end of downtime (potion, something on NIN, and BRD's
+// songs)
 struct TimelineIterator {
     downtime: TimespanSearch<()>,
     step: f64,
     current: f64,
     end: f64,
+    // Longest downtime a buff is held across, see `DowntimeConfig::hold_buffs`
+    hold: Option<f64>,
 }
 
 impl Iterator for TimelineIterator {
@@ -131,9 +189,20 @@ impl TimelineIterator {
     }
 
     fn next_span(&mut self, span: &Timespan) -> Option<f64> {
-        if !self.downtime.spans(span.end + self.current).is_empty() {
-            // End is in a downtime, reschedule
-            self.current += span.end;
+        match self.hold {
+            // Holds the buff until the end of a short enough downtime it would overlap
+            Some(hold) => {
+                let (begin, end) = (self.current + span.begin, self.current + span.end);
+                self.current = self.downtime.data.iter()
+                    .filter(|(downtime, _)| downtime.begin < end && begin < downtime.end)
+                    .filter(|(downtime, _)| downtime.end - downtime.begin <= hold)
+                    .map(|(downtime, _)| downtime.end)
+                    .fold(self.current, f64::max);
+            },
+            None => if !self.downtime.spans(span.end + self.current).is_empty() {
+                // End is in a downtime, reschedule
+                self.current += span.end;
+            },
         }
         self.next()
     }
@@ -144,6 +213,7 @@ impl TimelineIterator {
             end: timeline.end,
             current: start,
             step,
+            hold: timeline.downtime_config.hold_buffs,
         }
     }
 }
@@ -155,181 +225,64 @@ impl Timeline {
             end,
             comp_mind,
             buffs: TimespanSearch::new(),
-            timeline_cache: Mutex::new(HashMap::new()),
-        }
-    }
-
-    // BRD:
-    //  - Songs: Might change duration based on rotation
-    //      - Mage's Ballad -> 1% damage / 45s
-    //      - Army's Paeon -> 3% DH / 45s
-    //      - Wanderer's Minuet -> 2% crit / 45s
-    //      - Sequencing: 43s WM - 34s MB - 43s AP
-    //      - opener at ~.5s (first weave slot)
-    //  - Battle Voice -> 20% DH / 15s -> on raid buff window
-    //      - opener 3rd GCD/2nd weaving slot -> CD starts at ~6.5s
-    //      - window 120s
-    //  - Radiant Finale -> 2-4-6% damage / 15s -> on raid buff window, 2% on opener, 6% on buff
-    //  windows
-    //      - opener 3rd GCD/1st weaving slot -> CD starts at ~5.5s
-    //      - window 120s
-    //
-    // Not the exact song delaying strat used by BRDs but it's good enough for now.
-    pub fn with_brd(&mut self) -> &mut Self {
-        let song_sequence = [
-            (Timespan::new(0.0, 43.0), Buff::Critical(0.02)), // WM
-            (Timespan::new(0.0, 34.0), Buff::Damage(0.01)), // MB
-            (Timespan::new(0.0, 43.0), Buff::DirectHit(0.03)), // AP
-        ];
-
-        let mut song_iter = TimelineIterator::from_timeline(self, 0.5, 0.0);
-        for (span, song) in song_sequence.iter().cycle() {
-            // Compensate scheduling for downtime
-            if let Some(offset) = song_iter.next_with_step(span.end) {
-                self.buffs.push(span.clone().offset(offset), song.clone());
-            } else {
-                break;
-            }
+            dot: DotConfig::default(),
+            downtime_config: DowntimeConfig::default(),
+            timeline_cache: Arc::new(TimelineCache::new()),
         }
-
-        // Opener radiant finale
-        let radiant_finale = Timespan::new(0.0, 15.0);
-        let mut radiant_iter = TimelineIterator::from_timeline(self, 5.5, 120.0);
-        if let Some(offset) = radiant_iter.next() {
-            self.buffs.push(radiant_finale.clone().offset(offset), Buff::Damage(0.02));
-        }
-        // Other radiant finale
-        while let Some(offset) = radiant_iter.next_span(&radiant_finale) {
-            self.buffs.push(radiant_finale.clone().offset(offset), Buff::Damage(0.06));
-        }
-
-        let battle_voice = Timespan::new(0.0, 15.0);
-        let mut battle_voice_iter = TimelineIterator::from_timeline(self, 6.5, 120.0);
-        while let Some(offset) = battle_voice_iter.next_span(&battle_voice) {
-            self.buffs.push(battle_voice.clone().offset(offset), Buff::DirectHit(0.2));
-        }
-
-        self
     }
 
-    // DNC:
-    //  - Technical finish -> 1-2-3-5% damage / 20s, starts quite late after buff window
-    //      - tech step at 2nd GCD, then 4 steps * 1s then release -> ~6.5s
-    //      - drifted to finish GCD -> ~121s
-    pub fn with_dnc(&mut self) -> &mut Self {
-        let technical_finish = Timespan::new(0.0, 20.0);
-        let mut technical_finish_iter = TimelineIterator::from_timeline(self, 6.5, 121.0);
-        while let Some(offset) = technical_finish_iter.next_span(&technical_finish) {
-            self.buffs.push(technical_finish.clone().offset(offset), Buff::Damage(0.05));
-        }
-        self
-    }
-
-    // SMN:
-    //  - Searing Light -> 3% damage / 30s -> on raid buff window
-    //      - 1s prepull + 1st GCD 2nd weave -> ~2.5s
-    //      - window 120s
-    pub fn with_smn(&mut self) -> &mut Self {
-        let searing_light = Timespan::new(0.0, 30.0);
-        let mut searing_light_iter = TimelineIterator::from_timeline(self, 2.5, 120.0);
-        while let Some(offset) = searing_light_iter.next_span(&searing_light) {
-            self.buffs.push(searing_light.clone().offset(offset), Buff::Damage(0.03));
-        }
-        self
-    }
-
-    // RDM:
-    //  - Embolden -> 5% damage / 20s -> on raid buff window
-    //      - 3rd GCD 1st weave -> ~5.5s
-    //      - window 120s
-    pub fn with_rdm(&mut self) -> &mut Self {
-        let embolden = Timespan::new(0.0, 20.0);
-        let mut embolden_iter = TimelineIterator::from_timeline(self, 5.5, 120.0);
-        while let Some(offset) = embolden_iter.next_span(&embolden) {
-            self.buffs.push(embolden.clone().offset(offset), Buff::Damage(0.05));
-        }
+    pub fn with_dot_config(&mut self, dot: DotConfig) -> &mut Self {
+        self.dot = dot;
         self
     }
 
-    // MNK:
-    //  - Brotherhood -> 5% damage / 15s -> on raid buff window
-    //      - 4th gcd 1st weave -> 1.94*3+.5 -> 6.5s (GCD at ~1.94)
-    //      or
-    //      - 3rd gcd 1st weave -> 1.94*2+.5 -> 4.5s
-    //      - window 120s
-    // This function assumes 4th GCD 1st weave for better alignment
-    pub fn with_mnk(&mut self) -> &mut Self {
-        let brotherhood = Timespan::new(0.0, 15.0);
-        let mut brotherhood_iter = TimelineIterator::from_timeline(self, 6.5, 120.0);
-        while let Some(offset) = brotherhood_iter.next_span(&brotherhood) {
-            self.buffs.push(brotherhood.clone().offset(offset), Buff::Damage(0.05));
-        }
-        self
+    pub fn dot_config(&self) -> &DotConfig {
+        &self.dot
     }
 
-    // DRG:
-    //  - Battle Litany -> 10% crit / 15s -> on raid buff window
-    //      - 3rd gcd 1st weave -> 5.5s
-    //      - window 120s
-    pub fn with_drg(&mut self) -> &mut Self {
-        let battle_litany = Timespan::new(0.0, 15.0);
-        let mut battle_litany_iter = TimelineIterator::from_timeline(self, 5.5, 120.0);
-        while let Some(offset) = battle_litany_iter.next_span(&battle_litany) {
-            self.buffs.push(battle_litany.clone().offset(offset), Buff::Critical(0.1));
-        }
+    // Share the cast lists with other timelines, of the same fight or not
+    pub fn with_cache(&mut self, timeline_cache: Arc<TimelineCache>) -> &mut Self {
+        self.timeline_cache = timeline_cache;
         self
     }
 
-    // RPR:
-    //  - Arcane Circle -> 3% damage / 20s -> on raid buffs window
-    //      - prepull 1s, 1st GCD 1st weave -> 1.5s
-    //      - window 120s
-    pub fn with_rpr(&mut self) -> &mut Self {
-        let arcane_circle = Timespan::new(0.0, 20.0);
-        let mut arcane_circle_iter = TimelineIterator::from_timeline(self, 1.5, 120.0);
-        while let Some(offset) = arcane_circle_iter.next_span(&arcane_circle) {
-            self.buffs.push(arcane_circle.clone().offset(offset), Buff::Damage(0.03));
-        }
+    // Must be called before applying buffs !
+    pub fn with_downtime_config(&mut self, downtime_config: DowntimeConfig) -> &mut Self {
+        self.downtime_config = downtime_config;
         self
     }
 
-    // NIN:
-    //  - Mug -> 5% damage / 20s -> on raid buff window
-    //      - 1 clip 2nd GCD 1st weave -> ~3s (GCD at ~2.10)
-    //      - window 120s
-    pub fn with_nin(&mut self) -> &mut Self {
-        let mug = Timespan::new(0.0, 20.0);
-        let mut mug_iter = TimelineIterator::from_timeline(self, 3.0, 120.0);
-        while let Some(offset) = mug_iter.next_span(&mug) {
-            self.buffs.push(mug.clone().offset(offset), Buff::Damage(0.05));
+    // Schedules the buffs of raid_buffs.csv, or of any list in the same format
+    pub fn with_raid_buffs(&mut self, raid_buffs: &[RaidBuff]) -> &mut Self {
+        for raid_buff in raid_buffs.iter().filter(|raid_buff| raid_buff.alignment == Alignment::Window) {
+            let span = Timespan::new(0.0, raid_buff.duration);
+            let mut iter = TimelineIterator::from_timeline(self, raid_buff.opener, raid_buff.cooldown);
+            let mut buff = raid_buff.opener_buff();
+            while let Some(offset) = iter.next_span(&span) {
+                self.buffs.push(span.clone().offset(offset), buff);
+                buff = raid_buff.buff();
+            }
         }
-        self
-    }
 
-    // SCH:
-    //  - Chain Stratagem -> 10% critical / 15s -> on raid buff window
-    //      - prepull 1s + 3rd GCD (swifted) 1st weave -> 6.5s
-    //      or
-    //      - prepull 1s + 3rd GCD 2nd weave -> 7.5s
-    //      - window 121s (weaved)
-    pub fn with_sch(&mut self) -> &mut Self {
-        let chain_stratagem = Timespan::new(0.0, 15.0);
-        for offset in TimelineIterator::from_timeline(self, 6.5, 121.0) {
-            self.buffs.push(chain_stratagem.clone().offset(offset), Buff::Critical(0.1));
+        // Job by job in the order of the list, so that the same party always gets the same buffs
+        for job in raid_buff_jobs(raid_buffs) {
+            let sequence: Vec<_> = raid_buffs.iter()
+                .filter(|raid_buff| raid_buff.alignment == Alignment::Sequence && raid_buff.job == job)
+                .collect();
+            if sequence.is_empty() {
+                continue;
+            }
+            let mut iter = TimelineIterator::from_timeline(self, sequence[0].opener, 0.0);
+            for raid_buff in sequence.iter().cycle() {
+                // Compensate scheduling for downtime
+                if let Some(offset) = iter.next_with_step(raid_buff.duration) {
+                    self.buffs.push(Timespan::new(0.0, raid_buff.duration).offset(offset), raid_buff.buff());
+                } else {
+                    break;
+                }
+            }
         }
-        self
-    }
 
-    // AST:
-    //  - Divination -> 6% damage / 15s -> on raid buff window
-    //      - prepull 1s + 3rd GCD 1st weave -> 6.5s
-    //      - window 120s (weaved but lightspeed)
-    pub fn with_ast(&mut self) -> &mut Self {
-        let divination = Timespan::new(0.0, 15.0);
-        let mut divination_iter = TimelineIterator::from_timeline(self, 6.5, 120.0);
-        while let Some(offset) = divination_iter.next_span(&divination) {
-            self.buffs.push(divination.clone().offset(offset), Buff::Damage(0.06));
-        }
         self
     }
 
@@ -390,150 +343,63 @@ impl Timeline {
     // This has quite a massive impact on performance though because it's a per-gearset
     // optimization, not per-GCD
     //
-    // Edosis placement:
-    // - renew on the 12th or 13th GCD, depending on how much is clipped with the current GCD
-    // - Do not use if the next downtime is in less than dosis_potency/edosis_potency*3s, rounded
-    //      up (otherwise it's better to dosis)
-    // Phlegma placement:
-    // - 2 on opener, CD starts at prepull 1s + eukrasia+edosis + 2 GCD -> 1 + 1 + 2 + 1.5 + 2*GCD
-    //      so around ~10.5
-    // - phlegma used when about to cap
-    // - phlegma used immediately if there are more buffs ongoing than next GCD slot and there is a
-    //      phlegma available
-    // - on downtime, check if phlegma will overcap during downtime or not
-    //    - if it overcaps, check if the overcap is "fine" (eg, no use lost based on kill time)
-    //    - if it's not fine, use immediately
-    // - TODO find a better heuristic ?
+    // Edosis placement: see `edosis_plan`
+    // Phlegma placement: see `phlegma_plan`
     // Dosis placement:
     // - Every free GCD that is not edosis or phlegma
-    pub fn sge_timeline(&self, spell_speed: u32) -> Vec<(f64, SGEAction, SimplifiedBuff)> {
-        // TODO Timeline also depends on downtime/end time, right now it always uses the cached
-        // timelines even when those values are changed
+    pub fn sge_timeline(&self, spell_speed: u32) -> Arc<SGETimeline> {
         let stats = crate::data::Stats {
             spell_speed,
             ..Stats::default()
         };
         let gcd = stats.gcd().scalar();
-        if let Some(timeline) = self.timeline_cache.lock().unwrap().get(&((gcd*100.0) as usize)) {
-            return timeline.clone();
+        let fight = self.fight_key();
+        if let Some(timeline) = self.timeline_cache.get(&fight, stats.gcd().0) {
+            return timeline;
         }
         let gcd15 = stats.gcd15().scalar();
         let mut sge_timeline: Vec<(f64, Option<SGEAction>, Vec<Buff>)> = Vec::new();
 
-        let casts_per_cycle = (30.0 - 2.5) / gcd;
-        let early_refresh_casts = casts_per_cycle.floor();
-        let late_refresh_casts = casts_per_cycle.ceil();
-        // TODO make an edosis planning with early/late for the whole fight, and use raid buffs to
-        // check whether or not it's worth to early/late refresh
-        let early_refresh_pps = {
-            let cycle = gcd * early_refresh_casts + 2.5;
-            let dosis_per_second = early_refresh_casts / cycle;
-            let ticks_per_second = cycle.min(30.0) / cycle / 3.0;
-            dosis_per_second * 330.0 + ticks_per_second * 70.0
-        };
-        let late_refresh_pps = {
-            let cycle = gcd * late_refresh_casts + 2.5;
-            let dosis_per_second = late_refresh_casts / cycle;
-            let ticks_per_second = cycle.min(30.0) / cycle / 3.0;
-            dosis_per_second * 330.0 + ticks_per_second * 70.0
-        };
-        let casts_per_cycle = if early_refresh_pps >= late_refresh_pps {
-            // We're on an early refresh cycle
-            early_refresh_casts as usize
-        } else {
-            // We're on a late refresh cycle
-            late_refresh_casts as usize
-        };
-        let cycle_length = casts_per_cycle as f64 * gcd + 2.5;
-
-        // edosis and eukrasis usage
-        // TODO take into account the buffs when deciding whether to refresh ?
-        let mut edosis_iter = TimelineIterator::from_timeline(self, 1.0, cycle_length);
-        while let Some(offset) = edosis_iter.next() {
-            if let Some((next_downtime, _)) = self.downtime.next_start(offset) {
-                let edosis_ticks_before_downtime = (next_downtime.begin - offset) / 3.0;
-                if edosis_ticks_before_downtime * 70.0 < 330.0 {
-                    // Reserve time to cast eukrasis and skip this cast
-                    // 0.95 instead 1.0 to allow easy removing of actions that happen in downtime
-                    //   later. if it was 1.0, edosis cast would be = downtime.end and would be
-                    //   removed.
-                    edosis_iter.current = next_downtime.end - 0.95;
-                    continue;
-                }
-            }
-            let edosis_ticks_before_end = (self.end - offset) / 3.0;
-            if edosis_ticks_before_end * 70.0 < 330.0 {
-                // If refreshing edosis wouldn't be worth because of the end of the fight, stop
-                // scheduling dosis/eukrasis
-                break;
-            }
-            sge_timeline.push((offset, Some(SGEAction::Eukrasis), Vec::new()));
-            let buffs = self.buffs.spans(offset).into_iter().map(unwrap_tsearch).collect();
-            sge_timeline.push((offset+1.0, Some(SGEAction::Edosis), buffs));
-        }
+        let edosis_plan = self.edosis_plan(gcd);
 
-        // Now we've added all casts of e/edosis, add all the GCD casts that we'll fill in later
+        // edosis and eukrasis usage, and the GCDs until the next refresh that we'll fill in later
         // with phlegma and dosis
-        let mut casts: Vec<(f64, Option<SGEAction>, Vec<Buff>)> = Vec::new();
-        for (offset, _, _) in sge_timeline.iter().filter(|(_, action, _)| *action == Some(SGEAction::Edosis)) {
-            // edosis cast puts a recast of 1.5s on the GCD
-            let offset = offset + 1.5;
-            let mut cast_iter = TimelineIterator::from_timeline(self, offset, gcd);
-            if let Some(downtime) = self.downtime.next_start(offset) {
-                // Make sure we stop casting before the next downtime
-                cast_iter.end = downtime.0.begin;
-            }
-            for offset in cast_iter.take(casts_per_cycle) {
-                let buffs = self.buffs.spans(offset).into_iter().map(unwrap_tsearch).collect();
-                casts.push((offset, None, buffs));
+        for segment in edosis_plan.iter() {
+            // Before the first refresh: all of it when there isn't any, or while the edosis
+            // refreshed before downtime still runs
+            let first_refresh = segment.refreshes.first().map_or(segment.end, |refresh| refresh.instant);
+            sge_timeline.extend(gcd_slots(segment.start, first_refresh, gcd).map(|offset| (offset, None, self.buffs_vec(offset))));
+            for (index, refresh) in segment.refreshes.iter().enumerate() {
+                sge_timeline.push((refresh.instant, Some(SGEAction::Eukrasis), Vec::new()));
+                sge_timeline.push((refresh.instant + 1.0, Some(SGEAction::Edosis), self.buffs_vec(refresh.instant + 1.0)));
+                let casts_end = segment.refreshes.get(index + 1).map_or(segment.end, |next| next.instant);
+                // edosis cast puts a recast of 1.5s on the GCD
+                sge_timeline.extend(gcd_slots(refresh.instant + 2.5, casts_end, gcd).map(|offset| (offset, None, self.buffs_vec(offset))));
             }
         }
 
-        // Add those casts
-        sge_timeline.extend(casts.into_iter());
         // Add the prepull dosis
         sge_timeline.push((-gcd15, Some(SGEAction::Dosis), Vec::new()));
+        // And the ones precast before the end of downtime, landing just after it like edosis
+        if self.downtime_config.precast {
+            for (start, _) in self.segments().into_iter().filter(|(start, _)| *start > 1.0) {
+                let landing = start - 1.0;
+                sge_timeline.push((landing - gcd15, Some(SGEAction::Dosis), self.buffs_vec(landing)));
+            }
+        }
         // Sort the timeline by event
         sge_timeline.sort_by(|(a, _, _), (b, _, _)| a.partial_cmp(b).unwrap());
 
-        // opener: prepull dosis -> e -> edosis -> dosis -> dosis -> phlegma -> phlegma
-        // prepull dosis, e and edosis are already in
-        let mut unfilled_actions = sge_timeline.iter_mut().filter(|(_, action, _)| action.is_none());
-        // First two unfilled slot are dosis
-        unfilled_actions.next().unwrap().1 = Some(SGEAction::Dosis);
-        unfilled_actions.next().unwrap().1 = Some(SGEAction::Dosis);
-        // Next two unfilled slot are phlegma
-        // Also, first of those 2 is the start of the phlegma clock
-        let first_phlegma = unfilled_actions.next().unwrap();
-        first_phlegma.1 = Some(SGEAction::Phlegma);
-        let mut phlegma_clock = first_phlegma.0;
-        // TODO implement phlegma cap planning
-        unfilled_actions.next().unwrap().1 = Some(SGEAction::Phlegma);
-        std::mem::drop(unfilled_actions);
-
-        // Find when the next GCD where there is a phlegma charge
-        // And the first GCD where phlegma caps
-        // then in that GCD range, find the GCD with the most buffs
-        // Put phlegma here and arrange the clock accordingly
-        // If it can't be put here (downtime or something)
-        // Put it in the next available GCD
-        while phlegma_clock < self.end {
-            let phlegma_clock_stacked = phlegma_clock + PHLEGMA_CD;
-            let phlegma_clock_cap = phlegma_clock + PHLEGMA_CD*2.0;
-            // TODO optimize by not processing the array many times
-            let best_candidate = sge_timeline.iter_mut()
-                .skip_while(|(instant, _, _)| *instant < phlegma_clock_stacked)
-                .take_while(|(instant, _, _)| *instant <= phlegma_clock_cap)
-                .max_by(|(_, _, a), (_, _, b)| a.len().cmp(&b.len()));
-            if let Some(candidate) = best_candidate {
-                candidate.1 = Some(SGEAction::Phlegma);
-                phlegma_clock += PHLEGMA_CD;
-            } else if let Some(candidate) = sge_timeline.iter_mut().find(|(instant, action, _)| *instant >= phlegma_clock_cap && action.is_none()) {
-                candidate.1 = Some(SGEAction::Phlegma);
-                phlegma_clock = candidate.0;
-            } else {
-                // No more candidates for phlegma
-                break;
+        // Phlegma goes in free GCDs, the others are dosis
+        let slots: Vec<_> = sge_timeline.iter()
+            .filter(|(_, action, _)| action.is_none())
+            .map(|(instant, _, _)| *instant)
+            .collect();
+        let phlegma_plan = self.phlegma_plan(&slots, gcd15);
+        let mut phlegma_casts = phlegma_plan.iter().peekable();
+        for (instant, action, _) in sge_timeline.iter_mut().filter(|(_, action, _)| action.is_none()) {
+            if phlegma_casts.next_if(|cast| cast.instant == *instant).is_some() {
+                *action = Some(SGEAction::Phlegma);
             }
         }
 
@@ -547,16 +413,431 @@ impl Timeline {
 
         sge_timeline.retain(|(instant, _, _)| self.downtime.spans(*instant).is_empty());
 
-        let timeline: Vec<_>= sge_timeline.into_iter()
+        let casts = sge_timeline.into_iter()
             .map(|(instant, action, buffs)| (instant, action.unwrap(), buffs.simplify()))
             .collect();
+        let timeline = Arc::new(SGETimeline {
+            casts,
+            edosis_plan,
+            phlegma_plan,
+        });
 
-        self.timeline_cache.lock().unwrap().insert((gcd*100.0) as usize, timeline.clone());
+        self.timeline_cache.insert(fight, stats.gcd().0, timeline.clone());
 
         // TODO This needs testing.
 
         timeline
     }
+
+    // Rough value of the buffs on a hit: the cast list is the same for all the stat lines with the
+    // same GCD, so it can't know what a crit is worth. Crits are counted at +60%, and 223 mind (a
+    // potion) at about +7%.
+    fn buff_weight(&self, instant: f64) -> f64 {
+        self.buffs.data.iter()
+            .filter(|(span, _)| span.begin <= instant && instant < span.end)
+            .map(|(_, buff)| match buff {
+                Buff::Damage(damage) => 1.0 + damage,
+                Buff::Critical(critical) => 1.0 + critical * 0.6,
+                Buff::DirectHit(direct_hit) => 1.0 + direct_hit * 0.25,
+                Buff::Mind(mind) => 1.0 + *mind as f64 / 3100.0,
+            })
+            .product()
+    }
+
+    // Picks the free GCDs Phlegma goes in.
+    //
+    // Phlegma has 2 charges, one coming back every 40s while it isn't full, starting full at the
+    // pull. Every cast is worth its potency over dosis, weighted by the buffs (or all of it right
+    // before downtime, where dosis wouldn't finish casting), so the best plan casts as many as
+    // possible (no overcapping, spending everything before the kill, and using the downtime to
+    // recharge) in the best buff windows.
+    //
+    // This is a dynamic programming over the GCDs, the state being the charges and when the
+    // current charge started recharging. A charge can only start recharging on a cast, or when the
+    // previous one is back, so there are only a few of those states on every GCD.
+    fn phlegma_plan(&self, slots: &[f64], gcd15: f64) -> Vec<PhlegmaCast> {
+        #[derive(Clone, Copy)]
+        struct State {
+            charges: u32,
+            // When the next charge started recharging, None when full
+            recharge: Option<f64>,
+            score: f64,
+            // Index in the states of the previous GCD, and whether Phlegma was cast on this one
+            previous: usize,
+            cast: bool,
+        }
+
+        impl State {
+            fn at(mut self, instant: f64) -> Self {
+                while let Some(recharge) = self.recharge.filter(|recharge| recharge + PHLEGMA_CD <= instant) {
+                    self.charges += 1;
+                    self.recharge = (self.charges < PHLEGMA_CHARGES).then_some(recharge + PHLEGMA_CD);
+                }
+                self
+            }
+
+            fn key(&self) -> (u32, Option<i64>) {
+                (self.charges, self.recharge.map(|recharge| (recharge * 1000.0).round() as i64))
+            }
+        }
+
+        let mut layers = vec![vec![State {
+            charges: PHLEGMA_CHARGES,
+            recharge: None,
+            score: 0.0,
+            previous: 0,
+            cast: false,
+        }]];
+        for instant in slots.iter() {
+            let dosis = if self.in_downtime(instant + gcd15) { 0 } else { DOSIS.potency };
+            let gain = (PHLEGMA.potency - dosis) as f64 * self.buff_weight(*instant);
+            let mut layer: Vec<State> = Vec::new();
+            let mut keys: HashMap<_, usize> = HashMap::new();
+            for (previous, state) in layers.last().unwrap().iter().enumerate() {
+                let state = State { previous, cast: false, ..state.at(*instant) };
+                let cast = (state.charges > 0).then(|| State {
+                    charges: state.charges - 1,
+                    recharge: state.recharge.or(Some(*instant)),
+                    score: state.score + gain,
+                    cast: true,
+                    ..state
+                });
+                // Casting first keeps the earliest cast when it doesn't matter
+                for next in cast.into_iter().chain(std::iter::once(state)) {
+                    match keys.get(&next.key()) {
+                        Some(index) if layer[*index].score >= next.score => {},
+                        Some(index) => layer[*index] = next,
+                        None => {
+                            keys.insert(next.key(), layer.len());
+                            layer.push(next);
+                        },
+                    }
+                }
+            }
+            layers.push(layer);
+        }
+
+        // Walk back from the best plan
+        let mut best = layers.last().unwrap().iter().enumerate()
+            .max_by(|(_, a), (_, b)| a.score.partial_cmp(&b.score).unwrap())
+            .map(|(index, _)| index)
+            .unwrap();
+        let mut casts = Vec::new();
+        for (slot, layer) in layers.iter().enumerate().skip(1).rev() {
+            let state = layer[best];
+            if state.cast {
+                casts.push((slots[slot - 1], layers[slot - 1][state.previous].at(slots[slot - 1])));
+            }
+            best = state.previous;
+        }
+        casts.reverse();
+
+        casts.into_iter()
+            .map(|(instant, before)| {
+                let next_slot = slots.iter().find(|slot| **slot > instant).copied().unwrap_or(self.end);
+                let after = State {
+                    charges: before.charges - 1,
+                    recharge: before.recharge.or(Some(instant)),
+                    ..before
+                };
+                let next_charge = after.recharge.unwrap() + PHLEGMA_CD;
+                let downtime = self.downtime.data.iter()
+                    .map(|(span, _)| span.begin)
+                    .filter(|begin| *begin > instant)
+                    .fold(f64::INFINITY, f64::min);
+                let reason = if downtime <= next_slot {
+                    PhlegmaReason::BeforeDowntime
+                } else if before.charges == PHLEGMA_CHARGES || (before.charges == PHLEGMA_CHARGES - 1 && before.at(next_slot).charges == PHLEGMA_CHARGES) {
+                    PhlegmaReason::Overcap
+                } else if next_charge > self.end {
+                    PhlegmaReason::BeforeKill
+                } else if downtime < next_charge {
+                    PhlegmaReason::BeforeDowntime
+                } else if self.buff_weight(instant) > 1.0 {
+                    PhlegmaReason::BuffWindow
+                } else {
+                    PhlegmaReason::KeepRecharging
+                };
+                PhlegmaCast {
+                    instant,
+                    charges: before.charges,
+                    reason,
+                }
+            })
+            .collect()
+    }
+
+    fn fight_key(&self) -> FightKey {
+        FightKey {
+            kill_time: self.end.to_bits(),
+            downtime: self.downtime.data.iter()
+                .map(|(span, _)| (span.begin.to_bits(), span.end.to_bits()))
+                .collect(),
+            buffs: self.buffs.data.iter()
+                .map(|(span, buff)| (span.begin.to_bits(), span.end.to_bits(), buff.into()))
+                .collect(),
+            precast: self.downtime_config.precast,
+            refresh_before: self.downtime_config.refresh_before,
+        }
+    }
+
+    fn buffs_vec(&self, instant: f64) -> Vec<Buff> {
+        self.buffs.spans(instant).into_iter().map(unwrap_tsearch).collect()
+    }
+
+    // Stretches the boss can be hit in: from the first refresh after the pull or a downtime, to
+    // the next downtime or the kill. With a precast, the refresh after downtime waits for it to
+    // land, just like after the prepull dosis.
+    fn segments(&self) -> Vec<(f64, f64)> {
+        let mut segments = Vec::new();
+        let mut start = 1.0;
+        for (downtime, _) in self.downtime.begins.iter().map(|index| &self.downtime.data[*index]) {
+            if downtime.end <= start || downtime.begin >= self.end {
+                continue;
+            }
+            if downtime.begin > start {
+                segments.push((start, downtime.begin));
+            }
+            // 0.95 instead 1.0 to allow easy removing of actions that happen in downtime later. if
+            // it was 1.0, edosis cast would be = downtime.end and would be removed.
+            let refresh = if self.downtime_config.precast { downtime.end + 1.05 } else { downtime.end - 0.95 };
+            start = start.max(refresh);
+        }
+        if start < self.end {
+            segments.push((start, self.end));
+        }
+        segments
+    }
+
+    // Decides the refreshes of every segment of the fight, one after the other.
+    //
+    // A refresh is followed by either as many GCDs as fit in its 30s (early refresh, the DoT is
+    // refreshed before it runs out) or one more (late refresh, it drops for a bit). Which one is
+    // better depends on what's next: a late refresh can push the next ones into a buff window, an
+    // early one fits one more refresh before downtime. After the last refresh of a segment, the
+    // GCDs go on until the segment's end, and when refreshing isn't worth it anymore it doesn't
+    // happen at all.
+    //
+    // When refreshing before downtime is allowed, the last refresh can also wait for the last GCDs
+    // before it, and an edosis still running after the downtime delays the first refresh of the
+    // next segment if that's worth it.
+    pub fn edosis_plan(&self, gcd: f64) -> Vec<EdosisSegment> {
+        let mut plan: Vec<EdosisSegment> = Vec::new();
+        for (start, end) in self.segments() {
+            // Edosis applied in the previous segment, still running
+            let carried = plan.last()
+                .and_then(|segment| segment.refreshes.last())
+                .map(|refresh| refresh.instant + 1.0)
+                .filter(|application| self.downtime_config.refresh_before && application + EDOSIS_DURATION > start);
+            let delays = carried.map_or(0, |application| ((application + EDOSIS_DURATION - start) / gcd).ceil() as usize);
+            let (_, refreshes) = (0..=delays)
+                .map(|delay| {
+                    let first = start + delay as f64 * gcd;
+                    // The running edosis stops ticking when refreshed
+                    let lost = carried.map_or(0.0, |application| self.edosis_value(application, first + 1.0, application + EDOSIS_DURATION));
+                    let (score, refreshes) = self.segment_refreshes(first, end, gcd);
+                    (self.dosis_value(start, first, gcd) - lost + score, refreshes)
+                })
+                .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+                .unwrap();
+            plan.push(EdosisSegment {
+                start,
+                end,
+                refreshes,
+            });
+        }
+        plan
+    }
+
+    fn dosis_value(&self, from: f64, to: f64, gcd: f64) -> f64 {
+        gcd_slots(from, to, gcd)
+            .map(|offset| DOSIS.potency as f64 * self.buff_weight(offset))
+            .sum()
+    }
+
+    // Ticks of the edosis applied at `application` between `from` and `to`. Always with a random
+    // tick phase and the buffs at the application, as the plan is cached per `FightKey`, which
+    // doesn't have the DoT settings.
+    fn edosis_value(&self, application: f64, from: f64, to: f64) -> f64 {
+        let ticks: f64 = self.dot_ticks(from, to.min(application + EDOSIS_DURATION).min(self.end), None)
+            .iter()
+            .map(|(_, ticks)| ticks)
+            .sum();
+        ticks * EDOSIS_TICK.potency as f64 * self.buff_weight(application)
+    }
+
+    // Best refreshes of a segment starting with one at `start` (or none at all), and their score.
+    //
+    // This is a dynamic programming over (refreshes so far, late refreshes so far), which tells
+    // where the next refresh happens, scoring the potency of each option weighted by the buffs.
+    fn segment_refreshes(&self, start: f64, end: f64, gcd: f64) -> (f64, Vec<EdosisRefresh>) {
+        let early_casts = ((EDOSIS_DURATION - 2.5) / gcd).floor();
+        let late_casts = ((EDOSIS_DURATION - 2.5) / gcd).ceil();
+        let early_length = early_casts * gcd + 2.5;
+        // Whether the last refresh can wait for the last GCDs before downtime
+        let before_downtime = self.downtime_config.refresh_before && end < self.end;
+
+        let instant = |refreshes: usize, late: usize| start + refreshes as f64 * early_length + late as f64 * gcd;
+        let dosis = |from: f64, to: f64| self.dosis_value(from, to, gcd);
+        let edosis = |instant: f64, next: f64| {
+            let application = instant + 1.0;
+            self.edosis_value(application, application, next + 1.0)
+        };
+
+        // Best score and choice from each state, filled from the latest states
+        let max_refreshes = ((end - start) / early_length) as usize + 1;
+        let mut best = vec![vec![(0.0, None); max_refreshes + 1]; max_refreshes + 1];
+        for refreshes in (0..=max_refreshes).rev() {
+            for late in (0..=refreshes).rev() {
+                let current = instant(refreshes, late);
+                if current >= end {
+                    continue;
+                }
+                // Don't refresh and dosis until the end
+                let mut choice = (dosis(current, end), None);
+                // Edosis wouldn't land before downtime or the kill
+                if current + 1.0 >= end {
+                    best[refreshes][late] = choice;
+                    continue;
+                }
+                // Last refresh of the segment
+                let last = edosis(current, f64::INFINITY) + dosis(current + 2.5, end);
+                if last > choice.0 {
+                    choice = (last, Some(RefreshCycle::Last));
+                }
+                // Or on the last GCD edosis still lands on before downtime
+                if before_downtime {
+                    let waited = ((end - 1.0 - current) / gcd).ceil() as usize;
+                    if waited > 1 {
+                        let refresh = current + (waited - 1) as f64 * gcd;
+                        let score = dosis(current, refresh) + edosis(refresh, f64::INFINITY) + dosis(refresh + 2.5, end);
+                        if score > choice.0 {
+                            choice = (score, Some(RefreshCycle::BeforeDowntime));
+                        }
+                    }
+                }
+                for (cycle, next_late) in [(RefreshCycle::Early, late), (RefreshCycle::Late, late + 1)] {
+                    if cycle == RefreshCycle::Late && late_casts == early_casts {
+                        continue;
+                    }
+                    let next = instant(refreshes + 1, next_late);
+                    if next >= end || refreshes + 1 > max_refreshes {
+                        continue;
+                    }
+                    let score = edosis(current, next) + dosis(current + 2.5, next) + best[refreshes + 1][next_late].0;
+                    if score > choice.0 {
+                        choice = (score, Some(cycle));
+                    }
+                }
+                best[refreshes][late] = choice;
+            }
+        }
+
+        let mut refreshes = Vec::new();
+        let mut late = 0;
+        while let (_, Some(cycle)) = best[refreshes.len()][late] {
+            let mut refresh = instant(refreshes.len(), late);
+            if cycle == RefreshCycle::BeforeDowntime {
+                refresh += (((end - 1.0 - refresh) / gcd).ceil() - 1.0) * gcd;
+            }
+            refreshes.push(EdosisRefresh {
+                instant: refresh,
+                cycle,
+            });
+            match cycle {
+                RefreshCycle::Early => {},
+                RefreshCycle::Late => late += 1,
+                RefreshCycle::Last | RefreshCycle::BeforeDowntime => break,
+            }
+        }
+        (best[0][0].0, refreshes)
+    }
+}
+
+// GCDs from `from`, before `to`. The refreshes are on the same GCD grid, computed another way, so
+// one that's only a rounding error before `to` is the same GCD.
+fn gcd_slots(from: f64, to: f64, gcd: f64) -> impl Iterator<Item = f64> {
+    (0..).map(move |index| from + index as f64 * gcd)
+        .take_while(move |offset| *offset < to - 1e-6)
+}
+
+// The casts of a fight, with the edosis refreshes and phlegma casts they were built from
+pub struct SGETimeline {
+    pub casts: Vec<BuffedTimelineAction>,
+    pub edosis_plan: Vec<EdosisSegment>,
+    pub phlegma_plan: Vec<PhlegmaCast>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhlegmaReason {
+    // Both charges are up, or about to be
+    Overcap,
+    // The charge won't be back before the kill
+    BeforeKill,
+    // Last GCD before downtime, or the charge comes back during it
+    BeforeDowntime,
+    BuffWindow,
+    // Nothing better coming before the charge would cap
+    KeepRecharging,
+}
+
+impl std::fmt::Display for PhlegmaReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhlegmaReason::Overcap => write!(f, "Avoid overcapping"),
+            PhlegmaReason::BeforeKill => write!(f, "Spend before the kill"),
+            PhlegmaReason::BeforeDowntime => write!(f, "Recharge during downtime"),
+            PhlegmaReason::BuffWindow => write!(f, "Buff window"),
+            PhlegmaReason::KeepRecharging => write!(f, "Keep recharging"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PhlegmaCast {
+    pub instant: f64,
+    // Before the cast
+    pub charges: u32,
+    pub reason: PhlegmaReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshCycle {
+    // As many GCDs as fit in edosis' duration until the next refresh
+    Early,
+    // One more, letting edosis drop for a bit
+    Late,
+    // GCDs until downtime or the kill
+    Last,
+    // Dosis first, then refresh on the last GCD edosis lands on before downtime
+    BeforeDowntime,
+}
+
+impl std::fmt::Display for RefreshCycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshCycle::Early => write!(f, "Early"),
+            RefreshCycle::Late => write!(f, "Late"),
+            RefreshCycle::Last => write!(f, "Last"),
+            RefreshCycle::BeforeDowntime => write!(f, "Before downtime"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EdosisRefresh {
+    // When eukrasis is cast, edosis follows 1s later
+    pub instant: f64,
+    pub cycle: RefreshCycle,
+}
+
+// Refreshes between two downtimes, or the pull and the kill. No refresh when edosis isn't worth a
+// GCD in there.
+#[derive(Debug, Clone)]
+pub struct EdosisSegment {
+    pub start: f64,
+    pub end: f64,
+    pub refreshes: Vec<EdosisRefresh>,
 }
 
 fn unwrap_tsearch<T: Clone>((_, data): &(Timespan, T)) -> T {
@@ -585,114 +866,195 @@ trait BuffExt {
 
 impl BuffExt for Vec<Buff> {
     fn simplify(self) -> SimplifiedBuff {
-        let mut buff_damage = 0.0;
-        let mut buff_direct_hit = 0.0;
-        let mut buff_critical = 0.0;
+        let mut buff_hit = Buffs::default();
         let mut buff_mind = 0;
 
         self.into_iter()
             .for_each(|buff| {
                 match buff {
-                    Buff::Damage(damage) => buff_damage += damage,
-                    Buff::DirectHit(direct_hit) => buff_direct_hit += direct_hit,
-                    Buff::Critical(critical) => buff_critical += critical,
+                    // In percent, each damage buff is floored on its own
+                    Buff::Damage(damage) => buff_hit.damage.push(Unit((100.0 + damage * 100.0).round() as u32)),
+                    // Percentage points of direct hit and crit rate, in thousandths
+                    Buff::DirectHit(direct_hit) => buff_hit.direct_hit.0 += (direct_hit * 1000.0).round() as u32,
+                    Buff::Critical(critical) => buff_hit.critical.0 += (critical * 1000.0).round() as u32,
                     Buff::Mind(mind) => buff_mind += mind,
                 }
             });
 
         SimplifiedBuff {
-            damage: buff_damage,
-            direct_hit: buff_direct_hit,
-            critical: buff_critical,
+            hit: buff_hit,
             mind: buff_mind
         }
     }
 }
 
-trait StatExt {
-    fn edosis_damage_per_tick(&self) -> f64;
-    fn dosis_damage(&self) -> f64;
-    fn phlegma_damage(&self) -> f64;
+// Crit and direct hit buffs are on the rates, only mind changes the stats
+fn buffed_stats(stats: &Stats, buffs: &SimplifiedBuff) -> Stats {
+    let mut stats = stats.clone();
+    stats.mind += buffs.mind;
+    stats
+}
+
+// When the Eukrasian Dosis cast at `edosis_start` falls off: after 30s, or earlier if it gets
+// refreshed or the boss dies. It keeps running through downtime, its ticks there just don't land.
+fn edosis_end(tl: &Timeline, timeline: &[BuffedTimelineAction], edosis_start: f64) -> f64 {
+    let refresh = timeline.iter().find(|(instant, action, _)| *instant > edosis_start && *action == SGEAction::Edosis)
+        .map(|(instant, _, _)| *instant)
+        .unwrap_or(f64::INFINITY);
+    (edosis_start + EDOSIS_DURATION).min(refresh).min(tl.end)
 }
 
-use crate::utils::Scalable;
-impl<T: StatRepo> StatExt for T {
-    fn edosis_damage_per_tick(&self) -> f64 {
-        let adj_wd = self.adjusted_weapon_damage();
-        let map = self.magic_attack_power();
-        let det = self.det_multiplier();
-        let sps = self.sps_multiplier();
-        let trt = self.trait_bonus();
-        let damage = 70.scale(map).scale(det).scale(sps).scale(adj_wd).scale(trt) + 1;
-        damage as f64 * self.crit_factor() * self.dh_factor()
+impl Timeline {
+    fn in_downtime(&self, instant: f64) -> bool {
+        self.downtime.data.iter().any(|(span, _)| span.begin <= instant && instant < span.end)
+    }
+
+    // Same as the buffs of `spans`, without building the sets: this runs for every tick
+    fn buffs_at(&self, instant: f64) -> SimplifiedBuff {
+        self.buffs.data.iter()
+            .filter(|(span, _)| span.begin <= instant && instant < span.end)
+            .map(unwrap_tsearch)
+            .collect::<Vec<_>>()
+            .simplify()
     }
 
-    fn dosis_damage(&self) -> f64 {
-        let adj_wd = self.adjusted_weapon_damage();
-        let map = self.magic_attack_power();
-        let det = self.det_multiplier();
-        let trt = self.trait_bonus();
-        let damage = 330.scale(map).scale(det).scale(adj_wd).scale(trt);
-        damage as f64 * self.crit_factor() * self.dh_factor()
+    // Server ticks of a DoT running from `start` to `end`, as (instant, ticks) pairs.
+    //
+    // With a known phase, every tick landing in between that isn't in downtime counts once. With a
+    // random one, every instant of the DoT is as likely to get a tick, so each stretch out of
+    // downtime counts its length over 3s. Those stretches are cut on buff changes when ticks get
+    // the buffs up when they land.
+    fn dot_ticks(&self, start: f64, end: f64, phase: Option<f64>) -> Vec<(f64, f64)> {
+        if let Some(phase) = phase {
+            let mut tick = phase + ((start - phase) / SERVER_TICK).floor() * SERVER_TICK;
+            if tick <= start {
+                tick += SERVER_TICK;
+            }
+            let mut ticks = Vec::new();
+            while tick <= end {
+                if !self.in_downtime(tick) {
+                    ticks.push((tick, 1.0));
+                }
+                tick += SERVER_TICK;
+            }
+            return ticks;
+        }
+
+        let mut cuts = vec![start, end];
+        let mut cut_on = |span: &Timespan| {
+            cuts.extend([span.begin, span.end].into_iter().filter(|cut| start < *cut && *cut < end));
+        };
+        self.downtime.data.iter().for_each(|(span, _)| cut_on(span));
+        if self.dot.buffs == DotBuffs::PerTick {
+            self.buffs.data.iter().for_each(|(span, _)| cut_on(span));
+        }
+        cuts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        cuts.dedup();
+        cuts.into_iter().tuple_windows()
+            .map(|(begin, end)| ((begin + end) / 2.0, (end - begin) / SERVER_TICK))
+            .filter(|(middle, _)| !self.in_downtime(*middle))
+            .collect()
     }
+}
 
-    fn phlegma_damage(&self) -> f64 {
-        let adj_wd = self.adjusted_weapon_damage();
-        let map = self.magic_attack_power();
-        let det = self.det_multiplier();
-        let trt = self.trait_bonus();
-        let damage = PHLEGMA_POTENCY.scale(map).scale(det).scale(adj_wd).scale(trt);
-        damage as f64 * self.crit_factor() * self.dh_factor()
+// Phase of the server ticks, None when it's random
+fn tick_phase(tl: &Timeline) -> Option<f64> {
+    match tl.dot.tick_phase {
+        TickPhase::Fixed(phase) => Some(phase.rem_euclid(SERVER_TICK)),
+        TickPhase::Random => None,
+    }
+}
+
+// One damage instance of the cast list, before rolling for crits, direct hits and the damage
+// variance
+pub struct Hit {
+    // With the stat buffs
+    pub stats: Stats,
+    pub action: Action,
+    pub buffs: Buffs,
+    pub crit_rate: f64,
+    pub dh_rate: f64,
+    // How many times it lands: 1 for a cast, the ticks of a DoT with the same buffs
+    pub count: u32,
+}
+
+impl Timeline {
+    pub fn end(&self) -> f64 {
+        self.end
+    }
+
+    // Same cast list and buffs as `timeline_dps`, with the server ticks at `tick_phase`
+    pub fn hits(&self, stats: &Stats, tick_phase: f64) -> Vec<Hit> {
+        let mut stats = stats.clone();
+        stats.mind += (stats.mind as f64 * self.comp_mind) as u32;
+        let sge_timeline = self.sge_timeline(stats.spell_speed);
+        let timeline = &sge_timeline.casts;
+        let hit = |action: Action, buffs: &SimplifiedBuff, count| {
+            let stats = buffed_stats(&stats, buffs);
+            let (crit_rate, dh_rate) = chances(&stats, &action, &buffs.hit);
+            Hit {
+                stats,
+                action,
+                buffs: buffs.hit.clone(),
+                crit_rate,
+                dh_rate,
+                count,
+            }
+        };
+
+        let mut hits = Vec::new();
+        for (instant, action, buffs) in timeline.iter() {
+            match action {
+                SGEAction::Edosis => {
+                    let ticks = self.dot_ticks(*instant, edosis_end(self, timeline, *instant), Some(tick_phase.rem_euclid(SERVER_TICK)));
+                    match self.dot.buffs {
+                        DotBuffs::Snapshot => hits.push(hit(EDOSIS_TICK, buffs, ticks.len() as u32)),
+                        DotBuffs::PerTick => hits.extend(ticks.iter().map(|(tick, _)| hit(EDOSIS_TICK, &self.buffs_at(*tick), 1))),
+                    }
+                },
+                SGEAction::Dosis => hits.push(hit(DOSIS, buffs, 1)),
+                SGEAction::Phlegma => hits.push(hit(PHLEGMA, buffs, 1)),
+                SGEAction::Eukrasis => {},
+            }
+        }
+        hits
     }
 }
 
 // WORKS ONLY IF THE BUFFS HAVE BEEN SIMPLIFIED
 // which they should be
-pub fn timeline_dps(tl: &Timeline, gearset: &Gearset) -> f64 {
-    let mut stats = gearset.stats();
+pub fn timeline_dps(tl: &Timeline, stats: &Stats) -> f64 {
+    let mut stats = stats.clone();
     // Take into account the composition MND buff
     stats.mind += (stats.mind as f64 * tl.comp_mind)  as u32;
-    let timeline = tl.sge_timeline(stats.spell_speed);
-
-    let mut edosis_ticks = Vec::new();
-    let mut edosis_tick = 1.5;
-    while edosis_tick < tl.end {
-        edosis_ticks.push(edosis_tick);
-        edosis_tick += 3.0;
-    }
+    let sge_timeline = tl.sge_timeline(stats.spell_speed);
+    let timeline = &sge_timeline.casts;
+    let tick_phase = tick_phase(tl);
     let mut damage = 0.0;
 
-    // Get dosis damage, based on the duration of edosis / 3.0 (average number of ticks in an edosis
-    // cast).
+    // Get edosis damage from the server ticks landing while it's up
     for (edosis_start, _, buffs) in timeline.iter().filter(|(_, action, _)| *action == SGEAction::Edosis) {
-        // Take into account next event: downtime, kill, or refresh
-        let next_event = timeline.iter().find(|(instant, action, _)| *instant > *edosis_start && *action == SGEAction::Edosis)
-            .map(|(instant, _, _)| *instant)
-            .or_else(|| tl.downtime.next_start(*edosis_start).map(|(t, _)| t.begin))
-            .unwrap_or(tl.end);
-        // Find max duration if it would be rewritten, or interrupted by downtime or kill time
-        // Or 30s at maximum
-        // TODO this doesn't take into account "short" downtimes where the DoT would overlap the
-        // downtime without being refreshed
-        let edosis_length = (next_event - edosis_start)
-            .min(30.0);
-
-        let mut stats = stats.clone();
-        stats.critical += (buffs.critical * stats.critical as f64) as u32;
-        stats.direct_hit += (buffs.direct_hit * stats.direct_hit as f64) as u32;
-        stats.mind += buffs.mind;
-        damage += (stats.edosis_damage_per_tick() * (1.0 + buffs.damage) * edosis_length / 3.0).trunc();
+        let ticks = tl.dot_ticks(*edosis_start, edosis_end(tl, timeline, *edosis_start), tick_phase);
+        match tl.dot.buffs {
+            DotBuffs::Snapshot => {
+                let stats = buffed_stats(&stats, buffs);
+                let tick = expected_damage(&stats, &EDOSIS_TICK, &buffs.hit);
+                damage += (tick * ticks.iter().map(|(_, count)| count).sum::<f64>()).trunc();
+            },
+            DotBuffs::PerTick => for (instant, count) in ticks {
+                let buffs = tl.buffs_at(instant);
+                let stats = buffed_stats(&stats, &buffs);
+                damage += (expected_damage(&stats, &EDOSIS_TICK, &buffs.hit) * count).trunc();
+            },
+        }
     }
 
     // Get the damage for all other actions, meaning dosis and phlegma
-    for (_, action, buffs) in timeline {
-        let mut stats = stats.clone();
-        stats.critical += (buffs.critical * stats.critical as f64) as u32;
-        stats.direct_hit += (buffs.direct_hit * stats.direct_hit as f64) as u32;
-        stats.mind += buffs.mind;
+    for (_, action, buffs) in timeline.iter() {
+        let stats = buffed_stats(&stats, buffs);
         damage += match action {
-            SGEAction::Dosis => (stats.dosis_damage() * (1.0 + buffs.damage)).trunc(),
-            SGEAction::Phlegma => (stats.phlegma_damage() * (1.0 + buffs.damage)).trunc(),
+            SGEAction::Dosis => expected_damage(&stats, &DOSIS, &buffs.hit).trunc(),
+            SGEAction::Phlegma => expected_damage(&stats, &PHLEGMA, &buffs.hit).trunc(),
             // Skip eukrasia and edosis as we alreadi took those into account
             _ => {0.0}
         }
@@ -703,17 +1065,21 @@ pub fn timeline_dps(tl: &Timeline, gearset: &Gearset) -> f64 {
 }
 
 impl crate::solver::Evaluator for Timeline {
-    fn dps(&self, gearset: &Gearset) -> f64 {
-        timeline_dps(self, gearset)
+    fn stats_dps(&self, stats: &Stats) -> f64 {
+        timeline_dps(self, stats)
+    }
+
+    // The fight key has the raid buffs and potions as they were scheduled
+    fn settings(&self) -> String {
+        format!("Timeline {:?}, mind bonus {}, {:?}", self.fight_key(), self.comp_mind, self.dot)
     }
 }
 
 #[derive(Clone)]
 pub struct SimplifiedBuff {
-    damage: f64,
+    // Damage, crit rate and direct hit rate buffs
+    hit: Buffs,
     mind: u32,
-    direct_hit: f64,
-    critical: f64,
 }
 
 // TODO Replace this with ranges ?
@@ -738,3 +1104,226 @@ impl Timespan {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::damage::{damage, HitType, HIT_TYPES, VARIANCE};
+
+    const STATS: Stats = Stats {
+        weapon_damage: 132,
+        mind: 3100,
+        vitality: 3000,
+        piety: 390,
+        direct_hit: 1200,
+        critical: 2400,
+        determination: 1800,
+        spell_speed: 500,
+    };
+
+    // Average damage a crit adds to a hit, over the direct hit chance and the variance
+    fn crit_bonus(hit: &Hit) -> f64 {
+        HIT_TYPES.iter()
+            .filter(|hit_type| !hit_type.critical)
+            .map(|hit_type| {
+                let critical = HitType { critical: true, ..*hit_type };
+                let dh_chance = if hit_type.direct_hit { hit.dh_rate } else { 1.0 - hit.dh_rate };
+                let bonus: f64 = VARIANCE
+                    .map(|variance| {
+                        damage(&hit.stats, &hit.action, critical, variance, &hit.buffs) as f64
+                            - damage(&hit.stats, &hit.action, *hit_type, variance, &hit.buffs) as f64
+                    })
+                    .sum();
+                dh_chance * bonus / VARIANCE.count() as f64
+            })
+            .sum()
+    }
+
+    #[test]
+    fn critical_buff_raises_dps_by_its_rate() {
+        let unbuffed = Timeline::new(Vec::new(), 600.0, 0.0);
+        let mut buffed = Timeline::new(Vec::new(), 600.0, 0.0);
+        // Chain Stratagem, over the whole fight so that the cast list stays the same
+        buffed.buffs.push(Timespan::new(0.0, 600.0), Buff::Critical(0.1));
+
+        let casts = |timeline: &Timeline| timeline.sge_timeline(STATS.spell_speed).casts.iter()
+            .map(|(instant, action, _)| (instant.to_bits(), action.clone()))
+            .collect::<Vec<_>>();
+        assert!(casts(&unbuffed) == casts(&buffed));
+
+        // Every hit but the prepull dosis gets 10 more points of crit rate
+        let unbuffed_hits = unbuffed.hits(&STATS, 1.5);
+        let buffed_hits = buffed.hits(&STATS, 1.5);
+        let mut gain = 0.0;
+        for (unbuffed_hit, buffed_hit) in unbuffed_hits.iter().zip(&buffed_hits) {
+            if buffed_hit.buffs.critical.0 == 0 {
+                continue;
+            }
+            assert_eq!(buffed_hit.buffs.critical.0, 100);
+            assert!((buffed_hit.crit_rate - unbuffed_hit.crit_rate - 0.1).abs() < 1e-9);
+            gain += 0.1 * crit_bonus(unbuffed_hit) * unbuffed_hit.count as f64;
+        }
+        assert_eq!(buffed_hits.iter().filter(|hit| hit.buffs.critical.0 == 0).count(), 1);
+
+        // Hits are truncated one by one, by less than a point of damage each
+        let delta = timeline_dps(&buffed, &STATS) - timeline_dps(&unbuffed, &STATS);
+        let tolerance = 2.0 * buffed_hits.len() as f64 / 600.0;
+        assert!((delta - gain / 600.0).abs() < tolerance, "{} DPS instead of {}", delta, gain / 600.0);
+    }
+
+    fn tick_count(ticks: &[(f64, f64)]) -> f64 {
+        ticks.iter().map(|(_, ticks)| ticks).sum()
+    }
+
+    #[test]
+    fn dot_ticks_follow_the_server_grid() {
+        let timeline = Timeline::new(vec![Timespan::new(40.0, 50.0)], 90.0, 0.0);
+        // Edosis from 1s to 31s ticks at 1.5s, 4.5s, ... 28.5s
+        let ticks = timeline.dot_ticks(1.0, 31.0, Some(1.5));
+        assert_eq!(ticks.len(), 10);
+        assert!(ticks.iter().all(|(tick, _)| ((tick - 1.5) / SERVER_TICK).fract() == 0.0));
+        // A tick right on the application doesn't count, one right on the end does
+        assert_eq!(timeline.dot_ticks(1.5, 31.5, Some(1.5)).len(), 10);
+        // None of those landing in downtime, 40s to 50s
+        assert_eq!(timeline.dot_ticks(30.0, 60.0, Some(1.5)).len(), 6);
+        // A random phase counts the time out of downtime
+        assert!((tick_count(&timeline.dot_ticks(1.0, 31.0, None)) - 10.0).abs() < 1e-9);
+        assert!((tick_count(&timeline.dot_ticks(30.0, 60.0, None)) - 20.0 / 3.0).abs() < 1e-9);
+
+        let mut wrapped = Timeline::new(Vec::new(), 90.0, 0.0);
+        wrapped.with_dot_config(DotConfig { tick_phase: TickPhase::Fixed(4.5), ..DotConfig::default() });
+        assert_eq!(tick_phase(&wrapped), Some(1.5));
+    }
+
+    #[test]
+    fn random_tick_phase_averages_fixed_ones() {
+        let timeline = Timeline::new(vec![Timespan::new(100.0, 130.0)], 300.0, 0.0);
+        let casts = &timeline.sge_timeline(STATS.spell_speed).casts;
+        let phases = 300;
+        for (start, _, _) in casts.iter().filter(|(_, action, _)| *action == SGEAction::Edosis) {
+            let end = edosis_end(&timeline, casts, *start);
+            let average = (0..phases)
+                .map(|phase| timeline.dot_ticks(*start, end, Some(phase as f64 * SERVER_TICK / phases as f64)).len() as f64)
+                .sum::<f64>() / phases as f64;
+            let random = tick_count(&timeline.dot_ticks(*start, end, None));
+            assert!((average - random).abs() < 0.01, "{} ticks on average, {} with a random phase", average, random);
+        }
+        // Over the whole kill time, with 30s of downtime
+        let ticks = timeline.hits(&STATS, 1.5).iter()
+            .filter(|hit| hit.action.potency == EDOSIS_TICK.potency)
+            .map(|hit| hit.count)
+            .sum::<u32>();
+        assert!(ticks > 0 && ticks as f64 <= (300.0 - 30.0) / SERVER_TICK);
+    }
+
+    // Potency of a segment with these refreshes, weighted by the buffs, each edosis ticking until
+    // the next refresh
+    fn refreshes_value(timeline: &Timeline, start: f64, end: f64, refreshes: &[f64], gcd: f64) -> f64 {
+        let first = refreshes.first().copied().unwrap_or(end);
+        let mut value = timeline.dosis_value(start, first, gcd);
+        for (index, refresh) in refreshes.iter().enumerate() {
+            let next = refreshes.get(index + 1).copied();
+            let application = refresh + 1.0;
+            value += timeline.edosis_value(application, application, next.map_or(f64::INFINITY, |next| next + 1.0));
+            value += timeline.dosis_value(refresh + 2.5, next.unwrap_or(end), gcd);
+        }
+        value
+    }
+
+    // Every chain of early and late refreshes from `refreshes`, stopping anywhere
+    fn refresh_chains(refreshes: Vec<f64>, end: f64, early: f64, late: f64, chains: &mut Vec<Vec<f64>>) {
+        let last = *refreshes.last().unwrap();
+        for next in [last + early, last + late] {
+            if next + 1.0 < end {
+                let mut chain = refreshes.clone();
+                chain.push(next);
+                refresh_chains(chain, end, early, late, chains);
+            }
+        }
+        chains.push(refreshes);
+    }
+
+    #[test]
+    fn edosis_plan_is_the_best_chain() {
+        let mut timeline = Timeline::new(Vec::new(), 100.0, 0.0);
+        timeline.buffs.push(Timespan::new(40.0, 60.0), Buff::Damage(0.2));
+        let gcd = 2.4;
+        let early = ((EDOSIS_DURATION - 2.5) / gcd).floor() * gcd + 2.5;
+        let late = early + gcd;
+
+        let plan = timeline.edosis_plan(gcd);
+        assert_eq!(plan.len(), 1);
+        let segment = &plan[0];
+        let planned: Vec<_> = segment.refreshes.iter().map(|refresh| refresh.instant).collect();
+        // Never clipping edosis more than an early refresh does
+        assert!(planned.windows(2).all(|pair| pair[1] - pair[0] >= early - 1e-6));
+
+        let mut chains = vec![Vec::new()];
+        refresh_chains(vec![segment.start], segment.end, early, late, &mut chains);
+        let best = refreshes_value(&timeline, segment.start, segment.end, &planned, gcd);
+        for chain in chains {
+            let value = refreshes_value(&timeline, segment.start, segment.end, &chain, gcd);
+            assert!(value <= best + 1e-6, "{:?} is worth {} over {} for {:?}", chain, value, best, planned);
+        }
+    }
+
+    #[test]
+    fn phlegma_never_exceeds_its_charges() {
+        let mut timeline = Timeline::new(vec![Timespan::new(120.0, 150.0)], 300.0, 0.0);
+        timeline.buffs.push(Timespan::new(60.0, 80.0), Buff::Damage(0.2));
+        let plan = &timeline.sge_timeline(STATS.spell_speed).phlegma_plan;
+
+        // Full at the pull, one charge back every 40s while it isn't full
+        let mut charges = PHLEGMA_CHARGES;
+        let mut recharge: Option<f64> = None;
+        for cast in plan {
+            while let Some(start) = recharge.filter(|start| start + PHLEGMA_CD <= cast.instant) {
+                charges += 1;
+                recharge = (charges < PHLEGMA_CHARGES).then_some(start + PHLEGMA_CD);
+            }
+            assert!(charges > 0, "Phlegma at {}s without a charge", cast.instant);
+            assert_eq!(cast.charges, charges);
+            charges -= 1;
+            recharge = recharge.or(Some(cast.instant));
+        }
+        assert!(plan.len() as u32 <= PHLEGMA_CHARGES + (300.0 / PHLEGMA_CD) as u32);
+        assert!(plan.iter().all(|cast| !timeline.in_downtime(cast.instant)));
+    }
+
+    // Edosis ticks over a fight with a short downtime from 50s to 55s, with the edosis they come
+    // from
+    fn downtime_ticks(downtime_config: DowntimeConfig) -> Vec<(f64, f64)> {
+        let mut timeline = Timeline::new(vec![Timespan::new(50.0, 55.0)], 120.0, 0.0);
+        timeline.with_downtime_config(downtime_config);
+        let casts = &timeline.sge_timeline(STATS.spell_speed).casts;
+        casts.iter()
+            .filter(|(_, action, _)| *action == SGEAction::Edosis)
+            .flat_map(|(start, _, _)| {
+                timeline.dot_ticks(*start, edosis_end(&timeline, casts, *start), Some(1.5))
+                    .into_iter()
+                    .map(move |(tick, _)| (*start, tick))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn downtime_options_change_dot_uptime() {
+        let ticks = downtime_ticks(DowntimeConfig::default());
+        // Refreshed as soon as the boss is back, nothing applied before ticks after the downtime
+        assert!(ticks.iter().all(|(start, tick)| *start > 55.0 || *tick < 50.0));
+        let first_after = |ticks: &[(f64, f64)]| ticks.iter()
+            .map(|(start, _)| *start)
+            .filter(|start| *start > 55.0)
+            .fold(f64::INFINITY, f64::min);
+        assert!(first_after(&ticks) < 56.0);
+
+        // Refreshed right before, it goes on ticking after the downtime and the refresh waits
+        let refresh_before = downtime_ticks(DowntimeConfig { refresh_before: true, ..DowntimeConfig::default() });
+        assert!(refresh_before.iter().any(|(start, tick)| *start < 50.0 && *tick > 55.0));
+        assert!(first_after(&refresh_before) > 70.0);
+
+        // A dosis lands first, which delays edosis by the time it takes to cast
+        let precast = downtime_ticks(DowntimeConfig { precast: true, ..DowntimeConfig::default() });
+        assert!((first_after(&precast) - first_after(&ticks) - 2.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::solver::{Buff, SGEAction, SGETimeline};
+
+// Everything the cast list of a fight depends on, apart from the GCD. Times are kept as their bits
+// to be hashable.
+//
+// The DoT settings aren't part of it: the edosis refreshes are planned with a random tick phase
+// (see `Timeline::edosis_value`) and snapshotted buffs, whatever the tick phase and buffs are set
+// to, so the cast list doesn't change with them. Only the damage computed from it does.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FightKey {
+    pub kill_time: u64,
+    pub downtime: Vec<(u64, u64)>,
+    // Span and buff, in the order they were scheduled
+    pub buffs: Vec<(u64, u64, BuffKey)>,
+    pub precast: bool,
+    pub refresh_before: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuffKey {
+    Damage(u64),
+    DirectHit(u64),
+    Critical(u64),
+    Mind(u32),
+}
+
+impl From<&Buff> for BuffKey {
+    fn from(buff: &Buff) -> Self {
+        match buff {
+            Buff::Damage(damage) => BuffKey::Damage(damage.to_bits()),
+            Buff::DirectHit(direct_hit) => BuffKey::DirectHit(direct_hit.to_bits()),
+            Buff::Critical(critical) => BuffKey::Critical(critical.to_bits()),
+            Buff::Mind(mind) => BuffKey::Mind(*mind),
+        }
+    }
+}
+
+// What the UI shows of a cached fight
+pub struct CachedFight {
+    pub kill_time: f64,
+    pub downtime: Vec<(f64, f64)>,
+    pub buffs: usize,
+    pub precast: bool,
+    pub refresh_before: bool,
+    // GCD (in hundredths of a second) and its cast list
+    pub timelines: Vec<(u32, Arc<SGETimeline>)>,
+}
+
+impl CachedFight {
+    pub fn count(&self, gcd_index: usize, action: SGEAction) -> usize {
+        self.timelines[gcd_index].1.casts.iter()
+            .filter(|(_, cast, _)| *cast == action)
+            .count()
+    }
+}
+
+// Fights kept before the least recently used one is dropped. Editing the fight in the UI makes a
+// new one at every change.
+const CACHED_FIGHTS: usize = 16;
+
+#[derive(Default)]
+struct CachedTimelines {
+    // Value of `Fights::clock` when the fight was last looked up
+    last_used: u64,
+    timelines: HashMap<u32, Arc<SGETimeline>>,
+}
+
+#[derive(Default)]
+struct Fights {
+    clock: u64,
+    fights: HashMap<FightKey, CachedTimelines>,
+}
+
+// Cast lists of the last fights evaluated, by fight and GCD. A timeline built with the same cache
+// reuses the cast lists of the same fight, so the cache can outlive a solver run and be shared by
+// the next ones.
+#[derive(Default)]
+pub struct TimelineCache {
+    fights: Mutex<Fights>,
+}
+
+impl TimelineCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, fight: &FightKey, gcd: u32) -> Option<Arc<SGETimeline>> {
+        let mut fights = self.fights.lock().unwrap();
+        fights.clock += 1;
+        let clock = fights.clock;
+        let cached = fights.fights.get_mut(fight)?;
+        cached.last_used = clock;
+        cached.timelines.get(&gcd).cloned()
+    }
+
+    pub fn insert(&self, fight: FightKey, gcd: u32, timeline: Arc<SGETimeline>) {
+        let mut fights = self.fights.lock().unwrap();
+        fights.clock += 1;
+        let clock = fights.clock;
+        if !fights.fights.contains_key(&fight) && fights.fights.len() >= CACHED_FIGHTS {
+            let oldest = fights.fights.iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(fight, _)| fight.clone());
+            if let Some(oldest) = oldest {
+                fights.fights.remove(&oldest);
+            }
+        }
+        let cached = fights.fights.entry(fight).or_default();
+        cached.last_used = clock;
+        cached.timelines.insert(gcd, timeline);
+    }
+
+    pub fn clear(&self) {
+        self.fights.lock().unwrap().fights.clear();
+    }
+
+    pub fn fights(&self) -> Vec<CachedFight> {
+        let mut fights: Vec<_> = self.fights.lock().unwrap().fights.iter()
+            .map(|(fight, cached)| {
+                let mut timelines: Vec<_> = cached.timelines.iter()
+                    .map(|(gcd, timeline)| (*gcd, timeline.clone()))
+                    .collect();
+                timelines.sort_by_key(|(gcd, _)| *gcd);
+                CachedFight {
+                    kill_time: f64::from_bits(fight.kill_time),
+                    downtime: fight.downtime.iter()
+                        .map(|(begin, end)| (f64::from_bits(*begin), f64::from_bits(*end)))
+                        .collect(),
+                    buffs: fight.buffs.len(),
+                    precast: fight.precast,
+                    refresh_before: fight.refresh_before,
+                    timelines,
+                }
+            })
+            .collect();
+        fights.sort_by(|a, b| a.kill_time.partial_cmp(&b.kill_time).unwrap());
+        fights
+    }
+}
@@ -1,10 +1,11 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::ops::BitOr;
 
 use eframe::egui;
 
-use crate::data::ITEM_SLOTS;
+use crate::data::{Stat, ITEM_SLOTS, STATS};
 use crate::solver::*;
 
 impl crate::data::Gearset {
@@ -101,6 +102,24 @@ impl crate::data::Gearset {
             });
         });
     }
+
+    // Only the items that differ from this gearset are listed
+    pub fn alternatives_ui(&self, ui: &mut egui::Ui) {
+        if self.alternatives.is_empty() {
+            return;
+        }
+        egui::CollapsingHeader::new(format!("{} other item lists with the same stats", self.alternatives.len()))
+            .show(ui, |ui| {
+                for alternative in &self.alternatives {
+                    let swaps: Vec<_> = ITEM_SLOTS.iter()
+                        .zip(self.items.iter().zip(alternative.iter()))
+                        .filter(|(_, (item, other))| item.name != other.name)
+                        .map(|(slot, (_, other))| format!("{}: {}", slot, other.name))
+                        .collect();
+                    ui.label(swaps.join(", "));
+                }
+            });
+    }
 }
 
 pub trait StatRepoUi {
@@ -205,17 +224,45 @@ impl eframe::App for Ui {
             match self.tab {
                 Tab::Configuration => self.solver_tab(ui),
                 Tab::Comparator => self.comparator_tab(ui),
+                Tab::Pareto => self.pareto_tab(ui),
+                Tab::WhatIf => self.what_if_tab(ui),
             }
         });
         ctx.request_repaint_after(std::time::Duration::from_millis(100));
     }
 }
 
+// Progress is flushed to the shared counter every PROGRESS_BATCH candidates
+const PROGRESS_BATCH: usize = 4096;
+
+#[derive(Clone)]
+struct Stage {
+    id: usize,
+    total: usize,
+    start: std::time::Instant,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum ResultsState {
+    #[default]
+    Final,
+    // The run was stopped before it could finish
+    Partial,
+    // Best gearsets so far of a run that is still going
+    Provisional,
+    // The run stopped on an error, the gearsets are the last ones it showed
+    Failed,
+}
+
 #[derive(Clone)]
 pub struct UiLink {
     status_text: Arc<Mutex<String>>,
     count: Arc<AtomicUsize>,
+    stage: Arc<Mutex<Stage>>,
     gearsets: Arc<Mutex<Vec<(crate::data::Gearset, f64)>>>,
+    results_state: Arc<Mutex<ResultsState>>,
+    constraint_costs: Arc<Mutex<Vec<ConstraintCost>>>,
+    item_impacts: Arc<Mutex<Vec<ItemImpact>>>,
 }
 
 impl UiLink {
@@ -223,7 +270,15 @@ impl UiLink {
         Self {
             status_text: Arc::default(),
             count: Arc::default(),
+            stage: Arc::new(Mutex::new(Stage {
+                id: 0,
+                total: 0,
+                start: std::time::Instant::now(),
+            })),
             gearsets: Arc::default(),
+            results_state: Arc::default(),
+            constraint_costs: Arc::default(),
+            item_impacts: Arc::default(),
         }
     }
 
@@ -233,29 +288,114 @@ impl UiLink {
         Ok(())
     }
 
-    pub fn increment(&self) -> eyre::Result<()> {
-        self.count.fetch_add(1, Ordering::Relaxed);
-        Ok(())
+    // Start a new solver stage, which is expected to process `total` candidates
+    pub fn stage(&self, message: impl ToString, total: usize) -> eyre::Result<()> {
+        let mut stage = self.stage.lock().unwrap();
+        stage.id += 1;
+        stage.total = total;
+        stage.start = std::time::Instant::now();
+        self.count.store(0, Ordering::Relaxed);
+        self.message(message)
     }
 
-    pub fn set_count(&self, count: usize) -> eyre::Result<()> {
-        self.count.store(count, Ordering::Relaxed);
+    // Batched counter for the current stage
+    pub fn progress(&self) -> Progress<'_> {
+        Progress {
+            ui_link: self,
+            stage_id: self.stage.lock().unwrap().id,
+            pending: std::cell::Cell::new(0),
+        }
+    }
+
+    fn new_gearsets(&self, gearsets: Vec<(crate::data::Gearset, f64)>, partial: bool) -> eyre::Result<()> {
+        let state = if partial { ResultsState::Partial } else { ResultsState::Final };
+        self.replace_gearsets(gearsets, state);
         Ok(())
     }
 
-    fn new_gearsets(&self, gearsets: Vec<(crate::data::Gearset, f64)>) -> eyre::Result<()> {
-        *self.gearsets.lock().unwrap() = gearsets;
+    // A cancelled run without complete gearsets leaves the ones shown. If they came from this run,
+    // they are partial results now.
+    fn keep_gearsets(&self) {
+        let _gearsets = self.gearsets.lock().unwrap();
+        let mut state = self.results_state.lock().unwrap();
+        if *state == ResultsState::Provisional {
+            *state = ResultsState::Partial;
+        }
+    }
+
+    // Gearsets stay as they were, the error goes to the status line
+    fn failed(&self, error: &eyre::Report) {
+        *self.results_state.lock().unwrap() = ResultsState::Failed;
+        *self.status_text.lock().unwrap() = format!("ERROR: Solver failed: {}", error);
+    }
+
+    fn constraint_costs(&self, costs: Vec<ConstraintCost>) {
+        *self.constraint_costs.lock().unwrap() = costs;
+    }
+
+    fn item_impacts(&self, impacts: Vec<ItemImpact>) {
+        *self.item_impacts.lock().unwrap() = impacts;
+    }
+
+    pub fn provisional_gearsets(&self, gearsets: Vec<(crate::data::Gearset, f64)>) -> eyre::Result<()> {
+        self.replace_gearsets(gearsets, ResultsState::Provisional);
         Ok(())
     }
+
+    // Gearsets and their state are swapped under the same lock, so the UI never sees final results
+    // flagged as provisional or the other way around
+    fn replace_gearsets(&self, gearsets: Vec<(crate::data::Gearset, f64)>, state: ResultsState) {
+        let mut current = self.gearsets.lock().unwrap();
+        *current = gearsets;
+        *self.results_state.lock().unwrap() = state;
+    }
+}
+
+pub struct Progress<'a> {
+    ui_link: &'a UiLink,
+    stage_id: usize,
+    pending: std::cell::Cell<usize>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn tick(&self) {
+        self.pending.set(self.pending.get() + 1);
+        if self.pending.get() >= PROGRESS_BATCH {
+            self.flush();
+        }
+    }
+
+    fn flush(&self) {
+        let pending = self.pending.replace(0);
+        // Don't count leftovers from a previous stage in the current one
+        if self.ui_link.stage.lock().unwrap().id == self.stage_id {
+            self.ui_link.count.fetch_add(pending, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<'a> Drop for Progress<'a> {
+    fn drop(&mut self) {
+        self.flush();
+    }
 }
 
 fn load_items() -> eyre::Result<Vec<crate::data::Item>> {
     const ITEMS: &str = include_str!("items.csv");
 
+    parse_items(ITEMS.as_bytes())
+}
+
+// Same format as the built-in items
+fn load_items_from(path: &str) -> eyre::Result<Vec<crate::data::Item>> {
+    parse_items(std::fs::File::open(path)?)
+}
+
+fn parse_items(reader: impl std::io::Read) -> eyre::Result<Vec<crate::data::Item>> {
     let csv_reader = csv::ReaderBuilder::new()
         .delimiter(b';')
         .quoting(false)
-        .from_reader(ITEMS.as_bytes());
+        .from_reader(reader);
 
     let records: Vec<_> = csv_reader.into_records()
         .collect::<Result<_, _>>()?;
@@ -264,57 +404,126 @@ fn load_items() -> eyre::Result<Vec<crate::data::Item>> {
         .collect::<Result<Vec<_>, _>>()
 }
 
+fn load_raid_buffs() -> eyre::Result<Vec<RaidBuff>> {
+    const RAID_BUFFS: &str = include_str!("raid_buffs.csv");
+
+    parse_raid_buffs(RAID_BUFFS.as_bytes())
+}
+
+// Same format as the built-in raid buffs
+fn load_raid_buffs_from(path: &str) -> eyre::Result<Vec<RaidBuff>> {
+    parse_raid_buffs(std::fs::File::open(path)?)
+}
+
+fn parse_raid_buffs(reader: impl std::io::Read) -> eyre::Result<Vec<RaidBuff>> {
+    let csv_reader = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .quoting(false)
+        .comment(Some(b'#'))
+        .from_reader(reader);
+
+    let records: Vec<_> = csv_reader.into_records()
+        .collect::<Result<_, _>>()?;
+    records.into_iter()
+        .map(RaidBuff::try_from)
+        .collect::<Result<Vec<_>, _>>()
+}
+
 #[derive(PartialEq, Eq)]
 enum Tab {
     Configuration,
     Comparator,
+    Pareto,
+    WhatIf,
 }
 
 pub struct TimelineConfig {
     mind_bonus: f64,
-    brd: bool,
-    dnc: bool,
-    smn: bool,
-    rdm: bool,
-    mnk: bool,
-    drg: bool,
-    rpr: bool,
-    nin: bool,
-    sch: bool,
-    ast: bool,
+    raid_buffs: Vec<RaidBuff>,
+    raid_buffs_path: String,
+    // Jobs of the party, only their raid buffs are applied
+    party: Vec<String>,
+    // What the custom buff form adds
+    custom_buff: RaidBuff,
     potions: bool,
     downtimes: Vec<Timespan>,
     kill_time: f64,
+    downtime: DowntimeConfig,
+    dot: DotConfig,
 }
 
 impl Default for TimelineConfig {
     fn default() -> Self {
         Self {
             mind_bonus: Default::default(),
-            brd: Default::default(),
-            dnc: Default::default(),
-            smn: Default::default(),
-            rdm: Default::default(),
-            mnk: Default::default(),
-            drg: Default::default(),
-            rpr: Default::default(),
-            nin: Default::default(),
-            sch: Default::default(),
-            ast: Default::default(),
+            raid_buffs: Default::default(),
+            raid_buffs_path: "raid_buffs.csv".to_string(),
+            party: Default::default(),
+            custom_buff: RaidBuff {
+                job: "Custom".to_string(),
+                name: "Custom buff".to_string(),
+                kind: BuffKind::Damage,
+                magnitude: 0.05,
+                opener_magnitude: None,
+                duration: 20.0,
+                cooldown: 120.0,
+                opener: 6.5,
+                alignment: Alignment::Window,
+            },
             potions: Default::default(),
             downtimes: Default::default(),
             kill_time: 600.0,
+            downtime: DowntimeConfig::default(),
+            dot: DotConfig::default(),
         }
     }
 }
 
+struct SolverRun {
+    handle: std::thread::JoinHandle<()>,
+    token: CancellationToken,
+}
+
+// Distributions kept before the oldest ones are dropped, dragging a what-if stat adds one per step
+const CACHED_DISTRIBUTIONS: usize = 256;
+
+// Evaluators of the comparator and what-if tabs, built once per evaluator configuration instead
+// of on every frame
+struct PreviewEvaluators {
+    timeline: Arc<Timeline>,
+    monte_carlo: MonteCarloEvaluator,
+    distributions: HashMap<crate::data::Stats, DpsDistribution>,
+}
+
+impl PreviewEvaluators {
+    fn distribution(&mut self, stats: &crate::data::Stats) -> &DpsDistribution {
+        if self.distributions.len() >= CACHED_DISTRIBUTIONS && !self.distributions.contains_key(stats) {
+            self.distributions.clear();
+        }
+        let monte_carlo = &self.monte_carlo;
+        self.distributions.entry(stats.clone())
+            .or_insert_with(|| monte_carlo.distribution(stats))
+    }
+}
+
 pub struct Ui {
     ui_link: UiLink,
+    solver_run: Option<SolverRun>,
+    time_budget_enabled: bool,
+    time_budget: f64,
 
-    selected_gearset_a: Option<usize>,
-    selected_gearset_b: Option<usize>,
+    // Kept by value: the results are replaced while the solver runs
+    selected_gearset_a: Option<crate::data::Gearset>,
+    selected_gearset_b: Option<crate::data::Gearset>,
 
     items: Vec<crate::data::Item>,
+    items_path: String,
+    prune_items: bool,
+    pruned_items: Vec<PrunedItem>,
+    // What the solver was built with, and what the last run was started with
+    solver_items: Vec<crate::data::Item>,
+    last_run_items: Option<Vec<crate::data::Item>>,
+    incremental: bool,
 
     solver: std::sync::Arc<dyn crate::solver::Solver + Send + Sync>,
     solver_type: crate::solver::SolverType,
@@ -322,28 +531,52 @@ pub struct Ui {
 
     split_config: SplitConfig,
     rolling_config: RollingConfig,
+    annealing_config: AnnealingConfig,
+    genetic_config: GeneticConfig,
     timeline_config: TimelineConfig,
+    // Outlives the solvers, so a run with the same fight reuses the cast lists of the previous ones
+    timeline_cache: Arc<TimelineCache>,
+    monte_carlo_config: MonteCarloConfig,
+    constraints: Constraints,
+    report_constraint_costs: bool,
+    pareto: bool,
+    pareto_objective: Objective,
+    diversity: DiversityConfig,
+    checkpoint: CheckpointConfig,
     config_changed: bool,
+    // Dropped whenever an evaluator setting changes
+    preview: Option<PreviewEvaluators>,
 
     tab: Tab,
+    what_if_stats: crate::data::Stats,
 }
 
 impl Ui {
     pub fn new(_cc: &eframe::CreationContext<'_>) -> eyre::Result<Self> {
         let items = load_items()?;
+        let (kept_items, pruned_items) = prune_dominated(items.clone(), &Constraints::default());
         let ui_link = UiLink::new();
         let evaluator = crate::solver::InfiniteDummyEvaluator::default();
         Ok(Self {
             ui_link: ui_link.clone(),
+            solver_run: None,
+            time_budget_enabled: false,
+            time_budget: 60.0,
 
             selected_gearset_a: None,
             selected_gearset_b: None,
 
-            items: items.clone(),
+            items,
+            items_path: "items.csv".to_string(),
+            prune_items: true,
+            pruned_items,
+            solver_items: kept_items.clone(),
+            last_run_items: None,
+            incremental: false,
 
             solver: std::sync::Arc::new(
                 crate::solver::RollingSolver::new(ui_link, Arc::new(evaluator))
-                    .with_items(items)
+                    .with_items(kept_items)
                     .with_config(RollingConfig::default())
             ),
             solver_type: crate::solver::SolverType::Rolling,
@@ -351,56 +584,181 @@ impl Ui {
 
             split_config: SplitConfig::default(),
             rolling_config: RollingConfig::default(),
-            timeline_config: TimelineConfig::default(),
+            annealing_config: AnnealingConfig::default(),
+            genetic_config: GeneticConfig::default(),
+            timeline_config: TimelineConfig {
+                raid_buffs: load_raid_buffs()?,
+                ..Default::default()
+            },
+            timeline_cache: Arc::new(TimelineCache::new()),
+            monte_carlo_config: MonteCarloConfig::default(),
+            constraints: Constraints::default(),
+            // Off by default, it runs the solver several times
+            report_constraint_costs: false,
+            pareto: false,
+            pareto_objective: Objective::Piety,
+            diversity: DiversityConfig::default(),
+            checkpoint: CheckpointConfig::default(),
             config_changed: false,
+            preview: None,
 
             tab: Tab::Configuration,
+            what_if_stats: SAGE_BASE,
         })
     }
 
+    fn build_timeline(&self) -> Timeline {
+        // P5S: vec![Timespan::new(255.0, 267.0)]
+        let mut timeline = Timeline::new(
+            self.timeline_config.downtimes.clone(),
+            self.timeline_config.kill_time,
+            self.timeline_config.mind_bonus,
+        );
+        timeline.with_cache(self.timeline_cache.clone());
+        timeline.with_downtime_config(self.timeline_config.downtime.clone());
+        let raid_buffs: Vec<_> = self.timeline_config.raid_buffs.iter()
+            .filter(|raid_buff| self.timeline_config.party.contains(&raid_buff.job))
+            .cloned()
+            .collect();
+        timeline.with_raid_buffs(&raid_buffs);
+        if self.timeline_config.potions { timeline.with_potions(); };
+        timeline.with_dot_config(self.timeline_config.dot.clone());
+        timeline
+    }
+
+    fn build_monte_carlo(&self) -> MonteCarloEvaluator {
+        MonteCarloEvaluator::new(Arc::new(self.build_timeline()), self.monte_carlo_config.clone())
+    }
+
+    fn preview(&mut self) -> &mut PreviewEvaluators {
+        if self.preview.is_none() {
+            let timeline = Arc::new(self.build_timeline());
+            self.preview = Some(PreviewEvaluators {
+                monte_carlo: MonteCarloEvaluator::new(timeline.clone(), self.monte_carlo_config.clone()),
+                timeline,
+                distributions: HashMap::new(),
+            });
+        }
+        self.preview.as_mut().unwrap()
+    }
+
+    fn build_evaluator(&self, evaluator_type: EvaluatorType) -> Arc<dyn Evaluator + Send+Sync> {
+        match evaluator_type {
+            EvaluatorType::InfiniteDummy => Arc::new(InfiniteDummyEvaluator::default()) as _,
+            EvaluatorType::Timeline => Arc::new(self.build_timeline()) as _,
+            EvaluatorType::MonteCarlo => Arc::new(self.build_monte_carlo()) as _,
+        }
+    }
+
     fn rebuild_solver(&mut self) {
         self.config_changed = false;
-        let evaluator = match self.evaluator_type {
-            EvaluatorType::InfiniteDummy => Arc::new(InfiniteDummyEvaluator::default()) as _,
-            EvaluatorType::Timeline => {
-                // P5S: vec![Timespan::new(255.0, 267.0)]
-                let mut timeline = Timeline::new(
-                    self.timeline_config.downtimes.clone(),
-                    self.timeline_config.kill_time,
-                    self.timeline_config.mind_bonus,
-                );
-                if self.timeline_config.brd { timeline.with_brd(); };
-                if self.timeline_config.dnc { timeline.with_dnc(); };
-                if self.timeline_config.smn { timeline.with_smn(); };
-                if self.timeline_config.rdm { timeline.with_rdm(); };
-                if self.timeline_config.mnk { timeline.with_mnk(); };
-                if self.timeline_config.drg { timeline.with_drg(); };
-                if self.timeline_config.rpr { timeline.with_rpr(); };
-                if self.timeline_config.nin { timeline.with_nin(); };
-                if self.timeline_config.sch { timeline.with_sch(); };
-                if self.timeline_config.ast { timeline.with_ast(); };
-                if self.timeline_config.potions { timeline.with_potions(); };
-                Arc::new(timeline) as _
+        let evaluator = self.build_evaluator(self.evaluator_type);
+        let items = if self.prune_items {
+            let constraints = if self.pareto {
+                self.pareto_objective.pruning_constraints(self.constraints.clone())
+            } else {
+                self.constraints.clone()
+            };
+            let (kept_items, pruned_items) = prune_dominated(self.items.clone(), &constraints);
+            self.pruned_items = pruned_items;
+            kept_items
+        } else {
+            self.pruned_items.clear();
+            self.items.clone()
+        };
+        self.solver_items = items.clone();
+        // Only final results are worth starting from
+        let previous_run = self.last_run_items.clone()
+            .filter(|_| self.incremental)
+            .filter(|_| *self.ui_link.results_state.lock().unwrap() == ResultsState::Final);
+        let build_solver = {
+            let ui_link = self.ui_link.clone();
+            let evaluator = evaluator.clone();
+            let solver_type = self.solver_type;
+            let split_config = self.split_config.clone();
+            let rolling_config = self.rolling_config.clone();
+            let annealing_config = self.annealing_config.clone();
+            let genetic_config = self.genetic_config.clone();
+            let diversity = self.diversity.clone();
+            // Runs of a wrapper solver would overwrite each other's checkpoints
+            let wrapped = self.pareto
+                || self.constraints.per_gcd_tier
+                || (self.report_constraint_costs && self.constraints.is_active())
+                || previous_run.is_some();
+            let checkpoint = if wrapped { CheckpointConfig::default() } else { self.checkpoint.clone() };
+            move |items: Vec<crate::data::Item>, constraints: Constraints| -> Arc<dyn Solver + Send+Sync> {
+                match solver_type {
+                    SolverType::Split => Arc::new(
+                        SplitSolver::new(ui_link.clone(), evaluator.clone())
+                            .with_items(items)
+                            .with_config(split_config.clone())
+                            .with_constraints(constraints)
+                            .with_diversity(diversity.clone())
+                            .with_checkpoint(checkpoint.clone())
+                    ) as _,
+                    SolverType::Rolling => Arc::new(
+                        RollingSolver::new(ui_link.clone(), evaluator.clone())
+                            .with_items(items)
+                            .with_config(rolling_config.clone())
+                            .with_constraints(constraints)
+                            .with_diversity(diversity.clone())
+                            .with_checkpoint(checkpoint.clone())
+                    ) as _,
+                    SolverType::Annealing => Arc::new(
+                        AnnealingSolver::new(ui_link.clone(), evaluator.clone())
+                            .with_items(items)
+                            .with_config(annealing_config.clone())
+                            .with_constraints(constraints)
+                            .with_diversity(diversity.clone())
+                    ) as _,
+                    SolverType::Genetic => Arc::new(
+                        GeneticSolver::new(ui_link.clone(), evaluator.clone())
+                            .with_items(items)
+                            .with_config(genetic_config.clone())
+                            .with_constraints(constraints)
+                            .with_diversity(diversity.clone())
+                    ) as _,
+                }
             }
         };
-        let solver: Arc<dyn Solver + Send+Sync> = match self.solver_type {
-            SolverType::Split => Arc::new(
-                SplitSolver::new(self.ui_link.clone(), evaluator)
-                    .with_items(self.items.clone())
-                    .with_config(self.split_config.clone())
-            ) as _,
-            SolverType::Rolling => Arc::new(
-                RollingSolver::new(self.ui_link.clone(), evaluator)
-                    .with_items(self.items.clone())
-                    .with_config(self.rolling_config.clone())
-            ) as _,
+        let solver: Arc<dyn Solver + Send+Sync> = if self.pareto {
+            Arc::new(
+                ParetoSolver::new(self.ui_link.clone(), evaluator, build_solver)
+                    .with_items(items)
+                    .with_constraints(self.constraints.clone())
+                    .with_objective(self.pareto_objective)
+            )
+        } else if self.constraints.per_gcd_tier {
+            Arc::new(
+                GcdTierSolver::new(self.ui_link.clone(), evaluator, build_solver)
+                    .with_items(items)
+                    .with_constraints(self.constraints.clone())
+            )
+        } else if self.report_constraint_costs && self.constraints.is_active() {
+            Arc::new(
+                ConstraintCostSolver::new(self.ui_link.clone(), evaluator, build_solver)
+                    .with_items(items)
+                    .with_constraints(self.constraints.clone())
+            )
+        } else if let Some(previous_items) = previous_run {
+            let previous_gearsets = self.ui_link.gearsets.lock().unwrap().iter()
+                .map(|(gearset, _)| gearset.clone())
+                .collect();
+            Arc::new(
+                IncrementalSolver::new(self.ui_link.clone(), evaluator, build_solver)
+                    .with_items(items)
+                    .with_constraints(self.constraints.clone())
+                    .with_previous(previous_items, previous_gearsets)
+            )
+        } else {
+            build_solver(items, self.constraints.clone())
         };
 
         self.solver = solver;
         self.ui_link.gearsets.lock()
             .unwrap()
             .iter_mut()
-            .for_each(|(gearset, dps)| *dps = self.solver.dps(&gearset));
+            .for_each(|(gearset, dps)| *dps = self.solver.dps(gearset));
         self.ui_link.gearsets.lock().unwrap().sort_by(|(_, a), (_, b)| {
             b.partial_cmp(a).unwrap()
         });
@@ -410,6 +768,8 @@ impl Ui {
         ui.horizontal(|ui| {
             ui.selectable_value(&mut self.tab, Tab::Configuration, "Configuration");
             ui.selectable_value(&mut self.tab, Tab::Comparator, "Comparator");
+            ui.selectable_value(&mut self.tab, Tab::Pareto, "Pareto front");
+            ui.selectable_value(&mut self.tab, Tab::WhatIf, "What-if stats");
         });
     }
 
@@ -419,71 +779,191 @@ impl Ui {
                 ui.label("Solvers");
                 self.config_changed |= self.split_config_ui(ui).changed();
                 self.config_changed |= self.rolling_config_ui(ui).changed();
+                self.config_changed |= self.annealing_config_ui(ui).changed();
+                self.config_changed |= self.genetic_config_ui(ui).changed();
                 ui.end_row();
 
                 ui.label("Evaluators");
-                self.config_changed |= self.infinite_dummy_ui(ui).changed();
-                self.config_changed |= self.timeline_ui(ui).changed();
+                let evaluators = [
+                    self.infinite_dummy_ui(ui),
+                    self.timeline_ui(ui),
+                    self.monte_carlo_ui(ui),
+                ].into_iter().reduce(egui::Response::bitor).unwrap();
+                if evaluators.changed() {
+                    self.config_changed = true;
+                    self.preview = None;
+                }
+                ui.end_row();
+
+                ui.label("Items");
+                self.config_changed |= self.pruning_ui(ui).changed();
+                ui.end_row();
+
+                ui.label("Incremental");
+                self.config_changed |= self.incremental_ui(ui).changed();
+                ui.end_row();
+
+                ui.label("Constraints");
+                self.config_changed |= self.constraints_ui(ui).changed();
+                ui.end_row();
+
+                ui.label("Owned items");
+                self.config_changed |= self.owned_items_ui(ui).changed();
+                ui.end_row();
+
+                ui.label("Pareto front");
+                self.config_changed |= self.pareto_ui(ui).changed();
+                ui.end_row();
+
+                ui.label("Diversity");
+                self.config_changed |= self.diversity_ui(ui).changed();
+                ui.end_row();
+
+                ui.label("Checkpoints");
+                self.config_changed |= self.checkpoint_ui(ui).changed();
                 ui.end_row();
 
             });
 
             ui.separator();
 
-            if ui.button("Rebuild solver").clicked() {
-                if self.config_changed {
-                    self.rebuild_solver();
-                }
+            if ui.button("Rebuild solver").clicked() && self.config_changed {
+                self.rebuild_solver();
             }
 
-            if ui.button("Run solver").clicked() {
-                if self.config_changed {
-                    self.rebuild_solver();
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.time_budget_enabled, "Time budget");
+                ui.add_enabled(
+                    self.time_budget_enabled,
+                    egui::DragValue::new(&mut self.time_budget)
+                        .clamp_range(1.0..=86400.0)
+                        .suffix("s")
+                );
+            });
+
+            let running = self.solver_run.as_ref().is_some_and(|run| !run.handle.is_finished());
+            ui.horizontal(|ui| {
+                if ui.add_enabled(!running, egui::Button::new("Run solver")).clicked() {
+                    self.run_solver();
                 }
-                std::thread::spawn({
-                    let solver = self.solver.clone();
-                    let ui_link = self.ui_link.clone();
-                    move || {
-                        let gearsets = solver.solve().unwrap().into_iter()
-                            .map(|gearset| {
-                                let dps = solver.dps(&gearset);
-                                (gearset, dps)
-                            })
-                            .collect();
-                        ui_link.new_gearsets(gearsets).unwrap();
-                        ui_link.message("Finished!").unwrap();
+                if ui.add_enabled(running, egui::Button::new("Cancel")).clicked() {
+                    if let Some(run) = self.solver_run.as_ref() {
+                        run.token.cancel();
                     }
-                });
-            }
+                }
+            });
             if self.config_changed {
                 ui.colored_label(egui::Color32::YELLOW, "⚠ Solver configuration changed");
             }
         });
     }
 
-    fn comparator_tab(&mut self, ui: &mut egui::Ui) {
-        if let Some(index) = self.selected_gearset_a {
-            if let Some((gearset, _)) = self.ui_link.gearsets.lock().unwrap().get(index) {
-                ui.push_id("gearset_a", |ui| {
-                    gearset.table_ui(ui);
-                });
+    fn run_solver(&mut self) {
+        if self.config_changed {
+            self.rebuild_solver();
+        }
+        let mut token = CancellationToken::new();
+        if self.time_budget_enabled {
+            token = token.with_budget(std::time::Duration::from_secs_f64(self.time_budget));
+        }
+        self.last_run_items = Some(self.solver_items.clone());
+        // The error was the previous run's, its gearsets are still shown until the new run has some
+        {
+            let mut state = self.ui_link.results_state.lock().unwrap();
+            if *state == ResultsState::Failed {
+                *state = ResultsState::Partial;
             }
         }
-        ui.separator();
-        if let Some(index) = self.selected_gearset_b {
-            if let Some((gearset, _)) = self.ui_link.gearsets.lock().unwrap().get(index) {
-                ui.push_id("gearset_b", |ui| {
-                    gearset.table_ui(ui);
-                });
+        let handle = std::thread::spawn({
+            let solver = self.solver.clone();
+            let ui_link = self.ui_link.clone();
+            let token = token.clone();
+            move || {
+                let solution = match solver.solve(&token) {
+                    Ok(solution) => solution,
+                    Err(error) => {
+                        tracing::error!("Solver failed: {}", error);
+                        ui_link.failed(&error);
+                        return;
+                    }
+                };
+                if solution.partial && solution.gearsets.is_empty() {
+                    ui_link.keep_gearsets();
+                    ui_link.message("Stopped before any gearset was complete, showing the last complete ones").unwrap();
+                    return;
+                }
+                let gearsets = solution.gearsets.into_iter()
+                    .map(|gearset| {
+                        let dps = solver.dps(&gearset);
+                        (gearset, dps)
+                    })
+                    .collect();
+                ui_link.new_gearsets(gearsets, solution.partial).unwrap();
+                ui_link.constraint_costs(solution.constraint_costs);
+                ui_link.item_impacts(solution.item_impacts);
+                if solution.partial {
+                    ui_link.message("Stopped early, showing the best gearsets found so far").unwrap();
+                } else {
+                    ui_link.message("Finished!").unwrap();
+                }
             }
+        });
+        self.solver_run = Some(SolverRun { handle, token });
+    }
+
+    fn comparator_tab(&mut self, ui: &mut egui::Ui) {
+        // Only simulated while the evaluator is in use
+        let monte_carlo = self.evaluator_type == EvaluatorType::MonteCarlo;
+        if let Some(gearset) = self.selected_gearset_a.clone() {
+            ui.push_id("gearset_a", |ui| {
+                gearset.alternatives_ui(ui);
+                gearset.table_ui(ui);
+                if monte_carlo {
+                    self.preview().distribution(&gearset.stats()).ui(ui);
+                }
+            });
+        }
+        ui.separator();
+        if let Some(gearset) = self.selected_gearset_b.clone() {
+            ui.push_id("gearset_b", |ui| {
+                gearset.alternatives_ui(ui);
+                gearset.table_ui(ui);
+                if monte_carlo {
+                    self.preview().distribution(&gearset.stats()).ui(ui);
+                }
+            });
         }
     }
 
     fn status_bar(&mut self, ui: &mut egui::Ui) {
         ui.horizontal(|ui| {
-            ui.label(&self.ui_link.status_text.lock().unwrap().clone());
+            let status = self.ui_link.status_text.lock().unwrap().clone();
+            // The progress of a failed run won't move anymore
+            if *self.ui_link.results_state.lock().unwrap() == ResultsState::Failed {
+                ui.colored_label(egui::Color32::RED, status);
+                return;
+            }
+            ui.label(status);
+            ui.separator();
+            let count = self.ui_link.count.load(Ordering::Relaxed);
+            let stage = self.ui_link.stage.lock().unwrap().clone();
+            if stage.total == 0 {
+                ui.label(format!("{} items processed", count));
+                return;
+            }
+            let elapsed = stage.start.elapsed().as_secs_f64();
+            let throughput = count as f64 / elapsed;
+            ui.add(egui::ProgressBar::new(count as f32 / stage.total as f32)
+                .desired_width(200.0)
+                .text(format!("{} / {}", count, stage.total))
+            );
             ui.separator();
-            ui.label(format!("{} items processed", self.ui_link.count.load(Ordering::Relaxed)));
+            ui.label(format!("{:.0} items/s", throughput));
+            if count > 0 && count < stage.total {
+                ui.separator();
+                let eta = (stage.total - count) as f64 / throughput;
+                ui.label(format!("ETA {}", format_duration(eta)));
+            }
         });
     }
 
@@ -493,6 +973,23 @@ impl Ui {
         let text_size_body = egui::TextStyle::Button.resolve(ui.style()).size;
         let text_size_header = egui::TextStyle::Heading.resolve(ui.style()).size;
 
+        let state = {
+            let _gearsets = self.ui_link.gearsets.lock().unwrap();
+            *self.ui_link.results_state.lock().unwrap()
+        };
+        match state {
+            ResultsState::Final => {},
+            ResultsState::Partial => {
+                ui.colored_label(egui::Color32::YELLOW, "⚠ Partial results");
+            },
+            ResultsState::Provisional => {
+                ui.colored_label(egui::Color32::LIGHT_BLUE, "⏳ Provisional results");
+            },
+            ResultsState::Failed => {
+                ui.colored_label(egui::Color32::RED, "✖ The solver failed, these are the last results it showed");
+            },
+        }
+
         let table = TableBuilder::new(ui)
             .striped(true)
             .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
@@ -512,13 +1009,17 @@ impl Ui {
             });
         })
         .body(|mut body| {
-            for (index, (_, dps)) in self.ui_link.gearsets.lock().unwrap().iter().enumerate() {
+            for (gearset, dps) in self.ui_link.gearsets.lock().unwrap().iter() {
                 body.row(text_size_body, |mut row| {
                     row.col(|ui| {
-                        ui.radio_value(&mut self.selected_gearset_a, Some(index), "");
+                        if ui.radio(self.selected_gearset_a.as_ref() == Some(gearset), "").clicked() {
+                            self.selected_gearset_a = Some(gearset.clone());
+                        }
                     });
                     row.col(|ui| {
-                        ui.radio_value(&mut self.selected_gearset_b, Some(index), "");
+                        if ui.radio(self.selected_gearset_b.as_ref() == Some(gearset), "").clicked() {
+                            self.selected_gearset_b = Some(gearset.clone());
+                        }
                     });
                     row.col(|ui| {
                         ui.label(format!("{:.2}", dps));
@@ -529,6 +1030,15 @@ impl Ui {
     }
 }
 
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m{:02}s", minutes, seconds),
+        (hours, minutes, seconds) => format!("{}h{:02}m{:02}s", hours, minutes, seconds),
+    }
+}
+
 pub trait MatUi {
     fn row_ui(&self, row: &mut egui_extras::TableRow);
 }
@@ -597,11 +1107,323 @@ impl Ui {
             [
                 ui.selectable_value(&mut self.solver_type, SolverType::Rolling, "Rolling"),
                 ui.add(egui::Slider::new(&mut self.rolling_config.rolling_k, 1..=100000).text("Rolling K")),
+                ui.horizontal(|ui| {
+                    ui.label("Slot order");
+                    [
+                        ui.radio_value(&mut self.rolling_config.slot_order, SlotOrder::Adaptive, "Adaptive"),
+                        ui.radio_value(&mut self.rolling_config.slot_order, SlotOrder::Fixed, "Fixed"),
+                    ].into_iter().reduce(egui::Response::bitor).unwrap()
+                }).inner,
+                ui.checkbox(&mut self.rolling_config.optimistic_bound, "Optimistic bound")
+                    .on_hover_text("Rank partial gearsets as if their empty slots had the best stats available"),
             ].into_iter().reduce(egui::Response::bitor).unwrap()
         }).inner
     }
 }
 
+impl Ui {
+    fn annealing_config_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            [
+                ui.selectable_value(&mut self.solver_type, SolverType::Annealing, "Annealing"),
+                ui.add(egui::Slider::new(&mut self.annealing_config.iterations, 1..=1000000)
+                    .logarithmic(true)
+                    .text("Iterations")
+                ),
+                ui.add(egui::Slider::new(&mut self.annealing_config.restarts, 1..=100).text("Restarts")),
+                ui.add(egui::Slider::new(&mut self.annealing_config.initial_temperature, 0.1..=1000.0)
+                    .logarithmic(true)
+                    .text("Temperature")
+                ),
+                ui.add(egui::Slider::new(&mut self.annealing_config.k, 1..=1000).text("K")),
+                ui.horizontal(|ui| {
+                    ui.label("Seed");
+                    ui.add(egui::DragValue::new(&mut self.annealing_config.seed))
+                }).inner,
+            ].into_iter().reduce(egui::Response::bitor).unwrap()
+        }).inner
+    }
+}
+
+impl Ui {
+    fn genetic_config_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            [
+                ui.selectable_value(&mut self.solver_type, SolverType::Genetic, "Genetic"),
+                ui.add(egui::Slider::new(&mut self.genetic_config.population, 2..=10000)
+                    .logarithmic(true)
+                    .text("Population")
+                ),
+                ui.add(egui::Slider::new(&mut self.genetic_config.generations, 1..=10000)
+                    .logarithmic(true)
+                    .text("Generations")
+                ),
+                ui.add(egui::Slider::new(&mut self.genetic_config.elitism, 0..=100).text("Elitism")),
+                ui.checkbox(&mut self.genetic_config.clearing, "Clearing")
+                    .on_hover_text("Only the best of gearsets closer than the minimum item swaps (at least 1) is selected, the population spreads over distinct builds"),
+                ui.add(egui::Slider::new(&mut self.genetic_config.mutation_rate, 0.0..=1.0).text("Mutation rate")),
+                ui.add(egui::Slider::new(&mut self.genetic_config.tournament_size, 1..=16).text("Tournament size")),
+                ui.horizontal(|ui| {
+                    ui.label("Seed");
+                    ui.add(egui::DragValue::new(&mut self.genetic_config.seed))
+                }).inner,
+            ].into_iter().reduce(egui::Response::bitor).unwrap()
+        }).inner
+    }
+}
+
+impl Ui {
+    fn pruning_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let mut response = ui.checkbox(&mut self.prune_items, "Prune dominated items");
+            ui.horizontal(|ui| {
+                ui.label("File");
+                ui.text_edit_singleline(&mut self.items_path);
+                if ui.button("Reload").clicked() {
+                    match load_items_from(&self.items_path) {
+                        Ok(items) => {
+                            self.items = items;
+                            response.mark_changed();
+                        },
+                        Err(error) => {
+                            tracing::error!("Could not load {}: {}", self.items_path, error);
+                            self.ui_link.message(format!("Could not load {}: {}", self.items_path, error)).unwrap();
+                        },
+                    }
+                }
+            });
+            egui::CollapsingHeader::new(format!("{} pruned items", self.pruned_items.len()))
+                .show(ui, |ui| {
+                    for PrunedItem { item, reason } in self.pruned_items.iter() {
+                        ui.label(format!("{} - {}: {}", item.slot, item.name, reason));
+                    }
+                });
+            response
+        }).inner
+    }
+
+    // Counted by the unowned items constraint and Pareto objective
+    fn owned_items_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let owned_items = &mut self.constraints.owned_items;
+            let mut changed = false;
+            let mut response = egui::CollapsingHeader::new(format!("{} owned items", owned_items.len()))
+                .show(ui, |ui| {
+                    for item in self.items.iter().filter(|item| item.slot != crate::data::ItemSlot::Food) {
+                        let mut owned = owned_items.contains(&item.name);
+                        if ui.checkbox(&mut owned, format!("{} - {}", item.slot, item.name)).changed() {
+                            if owned {
+                                owned_items.insert(item.name.clone());
+                            } else {
+                                owned_items.remove(&item.name);
+                            }
+                            changed = true;
+                        }
+                    }
+                })
+                .header_response;
+            if changed {
+                response.mark_changed();
+            }
+            response
+        }).inner
+    }
+}
+
+impl Ui {
+    fn incremental_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let response = ui.checkbox(&mut self.incremental, "Only solve for new or changed items")
+                .on_hover_text("Starts from the last results, needs a finished run with the previous items. Not with per-tier, cost or Pareto runs.");
+            let impacts = self.ui_link.item_impacts.lock().unwrap();
+            egui::CollapsingHeader::new(format!("{} new item impacts", impacts.len()))
+                .default_open(true)
+                .show(ui, |ui| {
+                    ui.label("Estimates: each item is only tried with the items of the previous results");
+                    for ItemImpact { item, dps_gain } in impacts.iter() {
+                        match dps_gain {
+                            Some(dps_gain) if *dps_gain > 0.0 => ui.label(format!("{}: enters the BiS, +{:.2} DPS", item, dps_gain)),
+                            Some(dps_gain) => ui.label(format!("{}: {:.2} DPS short of the BiS", item, -dps_gain)),
+                            None => ui.label(format!("{}: none of the gearsets tried with it satisfies the constraints", item)),
+                        };
+                    }
+                });
+            response
+        }).inner
+    }
+}
+
+impl Ui {
+    fn constraints_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            // The constrained run, one without the constraints, and one per constraint alone when
+            // there are several
+            let cost_report_label = if self.constraints.is_active() {
+                let runs = match self.constraints.split().len() {
+                    0 | 1 => 2,
+                    constraints => 2 + constraints,
+                };
+                format!("Report DPS lost to each constraint ({} solver runs)", runs)
+            } else {
+                "Report DPS lost to each constraint".to_string()
+            };
+            let response = [
+                ui.horizontal(|ui| {
+                    ui.label("GCD");
+                    let mut gcd = self.constraints.gcd_target as f64 / 100.0;
+                    let response = [
+                        ui.radio_value(&mut self.constraints.gcd, None, "Any"),
+                        ui.radio_value(&mut self.constraints.gcd, Some(GcdConstraint::Equal), "Equal to"),
+                        ui.radio_value(&mut self.constraints.gcd, Some(GcdConstraint::AtMost), "At most"),
+                        ui.add_enabled(self.constraints.gcd.is_some(), egui::DragValue::new(&mut gcd)
+                            .speed(0.01)
+                            .clamp_range(1.5..=2.5)
+                            .fixed_decimals(2)
+                        ),
+                    ].into_iter().reduce(egui::Response::bitor).unwrap();
+                    self.constraints.gcd_target = (gcd * 100.0).round() as u32;
+                    response
+                }).inner,
+                ui.checkbox(&mut self.constraints.per_gcd_tier, "Best gearset per GCD tier")
+                    .on_hover_text("Solve every reachable GCD tier in the same run"),
+                self.stat_bounds_ui(ui),
+                ui.checkbox(&mut self.report_constraint_costs, cost_report_label)
+                    .on_hover_text("Runs the solver again without the constraints, then with each of them alone"),
+            ].into_iter().reduce(egui::Response::bitor).unwrap();
+            let costs = self.ui_link.constraint_costs.lock().unwrap();
+            for ConstraintCost { constraint, dps_lost } in costs.iter() {
+                match dps_lost {
+                    Some(dps_lost) => ui.label(format!("{}: -{:.2} DPS", constraint, dps_lost)),
+                    None => ui.label(format!("{}: unreachable", constraint)),
+                };
+            }
+            response
+        }).inner
+    }
+
+    fn stat_bounds_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        let mut responses = Vec::new();
+        let mut removed = None;
+        for (index, bound) in self.constraints.stat_bounds.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                let combo_box = egui::ComboBox::from_id_source(("stat_bound", index))
+                    .selected_text(bound.stat.to_string())
+                    .show_ui(ui, |ui| {
+                        STATS.into_iter()
+                            .map(|stat| ui.selectable_value(&mut bound.stat, stat, stat.to_string()))
+                            .reduce(egui::Response::bitor)
+                            .unwrap()
+                    });
+                let mut response = combo_box.response;
+                if combo_box.inner.is_some_and(|inner| inner.changed()) {
+                    response.mark_changed();
+                }
+                responses.push(response);
+                for (label, limit) in [("Min", &mut bound.min), ("Max", &mut bound.max)] {
+                    let mut enabled = limit.is_some();
+                    let mut value = limit.unwrap_or_default();
+                    responses.push(ui.checkbox(&mut enabled, label));
+                    responses.push(ui.add_enabled(enabled, egui::DragValue::new(&mut value)));
+                    *limit = enabled.then_some(value);
+                }
+                if ui.button("🗑").clicked() {
+                    removed = Some(index);
+                }
+            });
+        }
+
+        let mut response = ui.button("Add stat bound");
+        if response.clicked() {
+            self.constraints.stat_bounds.push(StatBound::new(Stat::Piety));
+            response.mark_changed();
+        }
+        if let Some(index) = removed {
+            self.constraints.stat_bounds.remove(index);
+            response.mark_changed();
+        }
+        responses.into_iter().fold(response, egui::Response::bitor)
+    }
+}
+
+impl Ui {
+    fn diversity_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.add(egui::Slider::new(&mut self.diversity.min_item_swaps, 0..=ITEM_SLOTS.len())
+            .text("Min item swaps")
+        ).on_hover_text("0 keeps every result, 1 merges the ones differing only by melds or food, more keeps results with different gear")
+    }
+
+    fn checkpoint_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let response = ui.checkbox(&mut self.checkpoint.enabled, "Save and resume")
+                .on_hover_text("Split and rolling solvers only, not with per-tier, cost or Pareto runs. A run with the same items and settings picks up where the checkpoint left off.");
+            let response = ui.add_enabled_ui(self.checkpoint.enabled, |ui| {
+                [
+                    ui.horizontal(|ui| {
+                        ui.label("File");
+                        ui.text_edit_singleline(&mut self.checkpoint.path)
+                    }).inner,
+                    ui.add(egui::Slider::new(&mut self.checkpoint.interval_secs, 10..=600).text("Interval (s)")),
+                ].into_iter().reduce(egui::Response::bitor).unwrap()
+            }).inner | response;
+            if self.checkpoint.enabled && std::path::Path::new(&self.checkpoint.path).exists() {
+                ui.horizontal(|ui| {
+                    ui.label("Found a checkpoint");
+                    if ui.button("🗑").on_hover_text("Start over").clicked() {
+                        if let Err(error) = std::fs::remove_file(&self.checkpoint.path) {
+                            tracing::warn!("Could not remove {}: {}", self.checkpoint.path, error);
+                        }
+                    }
+                });
+            }
+            response
+        }).inner
+    }
+}
+
+impl Ui {
+    fn pareto_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let response = ui.checkbox(&mut self.pareto, "DPS against")
+                .on_hover_text("One solver run per point of the front");
+            OBJECTIVES.into_iter()
+                .map(|objective| ui.radio_value(&mut self.pareto_objective, objective, objective.to_string()))
+                .fold(response, egui::Response::bitor)
+        }).inner
+    }
+
+    fn pareto_tab(&mut self, ui: &mut egui::Ui) {
+        use egui::plot::{Plot, PlotPoint, Points};
+
+        let points: Vec<_> = self.ui_link.gearsets.lock().unwrap().iter()
+            .map(|(gearset, dps)| [self.pareto_objective.value(gearset, &self.constraints) as f64, *dps])
+            .collect();
+
+        ui.label(format!("DPS against {}, click a gearset to compare it", self.pareto_objective));
+        let clicked = Plot::new("pareto_plot")
+            .label_formatter({
+                let objective = self.pareto_objective;
+                move |_, point| format!("{}: {}\nDPS: {:.2}", objective, point.x, point.y)
+            })
+            .show(ui, |plot_ui| {
+                plot_ui.points(Points::new(points.clone()).radius(4.0));
+                let pointer = plot_ui.pointer_coordinate().filter(|_| plot_ui.plot_clicked())?;
+                let pointer = plot_ui.screen_from_plot(pointer);
+                // Closest point on screen, if the click was close enough to it
+                points.iter().enumerate()
+                    .map(|(index, [x, y])| (index, plot_ui.screen_from_plot(PlotPoint::new(*x, *y)).distance(pointer)))
+                    .filter(|(_, distance)| *distance < 10.0)
+                    .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+                    .map(|(index, _)| index)
+            })
+            .inner;
+
+        if let Some(index) = clicked {
+            self.selected_gearset_a = self.ui_link.gearsets.lock().unwrap().get(index).map(|(gearset, _)| gearset.clone());
+            self.tab = Tab::Comparator;
+        }
+    }
+}
+
 impl Ui {
     fn infinite_dummy_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
         ui.vertical(|ui| {[
@@ -628,17 +1450,9 @@ impl Ui {
                 .suffix("%")
                 .custom_formatter(|num, _| format!("{:0}", num * 100.0))
             ),
-            ui.checkbox(&mut self.timeline_config.brd, "Bard"),
-            ui.checkbox(&mut self.timeline_config.dnc, "Dancer"),
-            ui.checkbox(&mut self.timeline_config.smn, "Summoner"),
-            ui.checkbox(&mut self.timeline_config.rdm, "Red Mage"),
-            ui.checkbox(&mut self.timeline_config.mnk, "Monk"),
-            ui.checkbox(&mut self.timeline_config.drg, "Dragoon"),
-            ui.checkbox(&mut self.timeline_config.rpr, "Reaper"),
-            ui.checkbox(&mut self.timeline_config.nin, "Ninja"),
-            ui.checkbox(&mut self.timeline_config.sch, "Scholar"),
-            ui.checkbox(&mut self.timeline_config.ast, "Astrologian"),
+            self.party_ui(ui),
             ui.checkbox(&mut self.timeline_config.potions, "Potions 🍶"),
+            self.raid_buffs_ui(ui),
             ui.separator(),
             ui.add(egui::Slider::new(&mut self.timeline_config.kill_time, 0.0..=1200.0)
                 .text("Kill time")
@@ -646,8 +1460,250 @@ impl Ui {
             ),
             ui.separator(),
             self.downtime_ui(ui),
+            ui.separator(),
+            self.dot_ui(ui),
         ].into_iter().reduce(egui::Response::bitor).unwrap()}).inner
     }
+
+    // One checkbox per job of the raid buff list
+    fn party_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        let config = &mut self.timeline_config;
+        raid_buff_jobs(&config.raid_buffs).into_iter()
+            .map(|job| {
+                let names = config.raid_buffs.iter()
+                    .filter(|raid_buff| raid_buff.job == job)
+                    .map(|raid_buff| raid_buff.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let mut in_party = config.party.contains(&job);
+                let response = ui.checkbox(&mut in_party, &job).on_hover_text(names);
+                if response.changed() {
+                    if in_party {
+                        config.party.push(job);
+                    } else {
+                        config.party.retain(|member| *member != job);
+                    }
+                }
+                response
+            })
+            .reduce(egui::Response::bitor)
+            .unwrap_or_else(|| ui.label("No raid buffs"))
+    }
+
+    fn raid_buffs_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        let collapsing = ui.collapsing("Raid buffs", |ui| {
+            let mut response = ui.horizontal(|ui| {
+                ui.label("File");
+                let mut response = ui.text_edit_singleline(&mut self.timeline_config.raid_buffs_path);
+                if ui.button("Reload").clicked() {
+                    let path = &self.timeline_config.raid_buffs_path;
+                    match load_raid_buffs_from(path) {
+                        Ok(raid_buffs) => {
+                            self.timeline_config.raid_buffs = raid_buffs;
+                            response.mark_changed();
+                        },
+                        Err(error) => {
+                            tracing::error!("Could not load {}: {}", path, error);
+                            self.ui_link.message(format!("Could not load {}: {}", path, error)).unwrap();
+                        },
+                    }
+                }
+                response
+            }).inner;
+
+            let raid_buffs = &mut self.timeline_config.raid_buffs;
+            let mut removed = None;
+            egui::Grid::new("raid_buffs").striped(true).show(ui, |ui| {
+                for (index, raid_buff) in raid_buffs.iter().enumerate() {
+                    ui.label(&raid_buff.job);
+                    ui.label(&raid_buff.name);
+                    ui.label(format!("{} {:.0}%", raid_buff.kind, raid_buff.magnitude * 100.0));
+                    ui.label(format!("{:.0}s", raid_buff.duration));
+                    ui.label(match raid_buff.alignment {
+                        Alignment::Window => format!("every {:.0}s from {:.1}s", raid_buff.cooldown, raid_buff.opener),
+                        Alignment::Sequence => format!("sequence from {:.1}s", raid_buff.opener),
+                    });
+                    if ui.small_button("🗑").clicked() {
+                        removed = Some(index);
+                    }
+                    ui.end_row();
+                }
+            });
+            if let Some(index) = removed {
+                raid_buffs.remove(index);
+                response.mark_changed();
+            }
+
+            ui.separator();
+            response | self.custom_buff_ui(ui)
+        });
+        let mut response = collapsing.header_response;
+        if collapsing.body_returned.is_some_and(|inner| inner.changed()) {
+            response.mark_changed();
+        }
+        response
+    }
+
+    fn custom_buff_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        let custom_buff = &mut self.timeline_config.custom_buff;
+        ui.label("Custom buff");
+        egui::Grid::new("custom_buff").show(ui, |ui| {
+            ui.label("Job");
+            ui.text_edit_singleline(&mut custom_buff.job);
+            ui.end_row();
+            ui.label("Name");
+            ui.text_edit_singleline(&mut custom_buff.name);
+            ui.end_row();
+            ui.label("Buff");
+            egui::ComboBox::from_id_source("custom_buff_kind")
+                .selected_text(custom_buff.kind.to_string())
+                .show_ui(ui, |ui| {
+                    for kind in [BuffKind::Damage, BuffKind::Critical, BuffKind::DirectHit] {
+                        ui.selectable_value(&mut custom_buff.kind, kind, kind.to_string());
+                    }
+                });
+            ui.end_row();
+            ui.label("Magnitude");
+            // In percent on screen, so that typing 5 means 5%: this egui has no custom parser
+            let mut percent = custom_buff.magnitude * 100.0;
+            if ui.add(egui::Slider::new(&mut percent, 0.0..=30.0).step_by(1.0).suffix("%")).changed() {
+                custom_buff.magnitude = percent / 100.0;
+            }
+            ui.end_row();
+            ui.label("Duration");
+            ui.add(egui::DragValue::new(&mut custom_buff.duration).clamp_range(1.0..=120.0).suffix("s"));
+            ui.end_row();
+            ui.label("Alignment");
+            egui::ComboBox::from_id_source("custom_buff_alignment")
+                .selected_text(custom_buff.alignment.to_string())
+                .show_ui(ui, |ui| {
+                    for alignment in [Alignment::Window, Alignment::Sequence] {
+                        ui.selectable_value(&mut custom_buff.alignment, alignment, alignment.to_string());
+                    }
+                });
+            ui.end_row();
+            ui.label("Cooldown");
+            ui.add_enabled(
+                custom_buff.alignment == Alignment::Window,
+                egui::DragValue::new(&mut custom_buff.cooldown).clamp_range(1.0..=600.0).suffix("s"),
+            );
+            ui.end_row();
+            ui.label("Opener");
+            ui.add(egui::DragValue::new(&mut custom_buff.opener).clamp_range(0.0..=120.0).speed(0.5).suffix("s"));
+            ui.end_row();
+        });
+
+        // Only adding the buff changes the timeline, not editing the form
+        let mut response = ui.button("Add");
+        if response.clicked() {
+            let custom_buff = custom_buff.clone();
+            if !self.timeline_config.party.contains(&custom_buff.job) {
+                self.timeline_config.party.push(custom_buff.job.clone());
+            }
+            self.timeline_config.raid_buffs.push(custom_buff);
+            response.mark_changed();
+        }
+        response
+    }
+
+    fn dot_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.label("Eukrasian Dosis");
+        let dot = &mut self.timeline_config.dot;
+        let combo_box = egui::ComboBox::from_label("Server tick phase")
+            .selected_text(dot.tick_phase.to_string())
+            .show_ui(ui, |ui| {
+                // Keep the phase when it is already fixed
+                let phase = match dot.tick_phase {
+                    TickPhase::Fixed(phase) => phase,
+                    TickPhase::Random => 1.5,
+                };
+                [
+                    ui.selectable_value(&mut dot.tick_phase, TickPhase::Fixed(phase), "Fixed"),
+                    ui.selectable_value(&mut dot.tick_phase, TickPhase::Random, "Random"),
+                ].into_iter().reduce(egui::Response::bitor).unwrap()
+            });
+        let mut response = combo_box.response;
+        if combo_box.inner.is_some_and(|inner| inner.changed()) {
+            response.mark_changed();
+        }
+        let mut responses = vec![response];
+        if let TickPhase::Fixed(phase) = &mut dot.tick_phase {
+            responses.push(ui.add(egui::Slider::new(phase, 0.0..=2.9)
+                .step_by(0.1)
+                .text("Phase")
+                .suffix("s")
+            ));
+        }
+        responses.push(ui.horizontal(|ui| {
+            ui.selectable_value(&mut dot.buffs, DotBuffs::Snapshot, "Snapshot buffs") |
+            ui.selectable_value(&mut dot.buffs, DotBuffs::PerTick, "Buffs per tick")
+        }).inner);
+        responses.into_iter().reduce(egui::Response::bitor).unwrap()
+    }
+}
+
+impl Ui {
+    fn monte_carlo_ui(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let mut responses = vec![
+                ui.selectable_value(
+                    &mut self.evaluator_type,
+                    EvaluatorType::MonteCarlo,
+                    "Monte Carlo (timeline)"
+                ),
+                ui.add(egui::Slider::new(&mut self.monte_carlo_config.runs, 1..=100000)
+                    .logarithmic(true)
+                    .text("Runs")
+                ),
+                ui.horizontal(|ui| {
+                    ui.label("Seed");
+                    ui.add(egui::DragValue::new(&mut self.monte_carlo_config.seed))
+                }).inner,
+            ];
+            let rank_by = &mut self.monte_carlo_config.rank_by;
+            let combo_box = egui::ComboBox::from_label("Rank by")
+                .selected_text(rank_by.to_string())
+                .show_ui(ui, |ui| {
+                    // Keep the percentile when it is already ranking by one
+                    let percentile = match *rank_by {
+                        DpsStatistic::Percentile(percentile) => percentile,
+                        DpsStatistic::Mean => 50.0,
+                    };
+                    [
+                        ui.selectable_value(rank_by, DpsStatistic::Mean, "Mean"),
+                        ui.selectable_value(rank_by, DpsStatistic::Percentile(percentile), "Percentile"),
+                    ].into_iter().reduce(egui::Response::bitor).unwrap()
+                });
+            let mut response = combo_box.response;
+            if combo_box.inner.is_some_and(|inner| inner.changed()) {
+                response.mark_changed();
+            }
+            responses.push(response);
+            if let DpsStatistic::Percentile(percentile) = rank_by {
+                responses.push(ui.add(egui::Slider::new(percentile, 0.0..=100.0).text("Percentile")));
+            }
+            responses.into_iter().reduce(egui::Response::bitor).unwrap()
+        }).inner
+    }
+}
+
+impl DpsDistribution {
+    fn ui(&self, ui: &mut egui::Ui) {
+        egui::Grid::new("dps_distribution").show(ui, |ui| {
+            for (label, dps) in [
+                ("Mean", self.mean),
+                ("Std dev", self.std_dev),
+                ("p25", self.p25),
+                ("p50", self.p50),
+                ("p75", self.p75),
+                ("p95", self.p95),
+            ] {
+                ui.label(label);
+                ui.label(format!("{:.2}", dps));
+                ui.end_row();
+            }
+        });
+    }
 }
 
 impl Ui {
@@ -689,6 +1745,193 @@ impl Ui {
             self.timeline_config.downtimes.push(Timespan::new(0.0, 0.0));
             response.mark_changed();
         }
+        let downtime = &mut self.timeline_config.downtime;
+        let mut hold = downtime.hold_buffs.is_some();
+        let mut hold_length = downtime.hold_buffs.unwrap_or(30.0);
+        let response = [
+            response,
+            ui.checkbox(&mut downtime.precast, "Precast dosis before the boss is back"),
+            ui.checkbox(&mut downtime.refresh_before, "Refresh edosis before downtime"),
+            ui.checkbox(&mut hold, "Hold raid buffs across downtime")
+                .on_hover_text("Otherwise a buff that would end in downtime is pushed back by its duration"),
+            ui.add_enabled(hold, egui::Slider::new(&mut hold_length, 0.0..=120.0)
+                .text("up to")
+                .suffix("s")
+            ),
+        ].into_iter().reduce(egui::Response::bitor).unwrap();
+        downtime.hold_buffs = hold.then_some(hold_length);
         response
     }
 }
+
+impl Ui {
+    fn what_if_tab(&mut self, ui: &mut egui::Ui) {
+        use crate::data::StatRepo;
+
+        ui.horizontal(|ui| {
+            ui.label("Start from");
+            if ui.button("Base stats").clicked() {
+                self.what_if_stats = SAGE_BASE;
+            }
+            let best = self.ui_link.gearsets.lock().unwrap().first().map(|(gearset, _)| gearset.clone());
+            let sources = [
+                ("Best gearset", best.as_ref()),
+                ("Gearset A", self.selected_gearset_a.as_ref()),
+                ("Gearset B", self.selected_gearset_b.as_ref()),
+            ];
+            let mut picked = None;
+            for (label, gearset) in sources {
+                if ui.add_enabled(gearset.is_some(), egui::Button::new(label)).clicked() {
+                    picked = gearset.map(|gearset| gearset.stats());
+                }
+            }
+            if let Some(stats) = picked {
+                self.what_if_stats = stats;
+            }
+        });
+
+        // Below the base stats, the formulas underflow
+        let stats = &mut self.what_if_stats;
+        egui::Grid::new("what_if_grid").show(ui, |ui| {
+            let fields = [
+                (Stat::WeaponDamage, &mut stats.weapon_damage, SAGE_BASE.weapon_damage),
+                (Stat::Mind, &mut stats.mind, SAGE_BASE.mind),
+                (Stat::Vitality, &mut stats.vitality, SAGE_BASE.vitality),
+                (Stat::Piety, &mut stats.piety, SAGE_BASE.piety),
+                (Stat::DirectHit, &mut stats.direct_hit, SAGE_BASE.direct_hit),
+                (Stat::Critical, &mut stats.critical, SAGE_BASE.critical),
+                (Stat::Determination, &mut stats.determination, SAGE_BASE.determination),
+                (Stat::SpellSpeed, &mut stats.spell_speed, SAGE_BASE.spell_speed),
+            ];
+            for (stat, value, min) in fields {
+                ui.label(stat.to_string());
+                ui.add(egui::DragValue::new(value).clamp_range(min..=10000));
+                ui.end_row();
+            }
+        });
+
+        ui.separator();
+        let stats = self.what_if_stats.clone();
+        ui.label(format!("GCD: {:.2}", stats.gcd().0 as f64 / 100.0));
+        let timeline = self.preview().timeline.clone();
+        ui.label(format!("Infinite Dummy: {:.2} DPS", InfiniteDummyEvaluator::default().stats_dps(&stats)));
+        ui.label(format!("Timeline: {:.2} DPS", timeline.stats_dps(&stats)));
+        if self.evaluator_type == EvaluatorType::MonteCarlo {
+            ui.label("Monte Carlo:");
+            self.preview().distribution(&stats).ui(ui);
+        }
+        let sge_timeline = timeline.sge_timeline(stats.spell_speed);
+        ui.collapsing("Eukrasian Dosis refreshes (timeline)", |ui| {
+            edosis_plan_ui(ui, &sge_timeline.edosis_plan);
+        });
+        ui.collapsing("Phlegma casts (timeline)", |ui| {
+            phlegma_plan_ui(ui, &sge_timeline.phlegma_plan);
+        });
+        ui.collapsing("Timeline cache", |ui| {
+            if ui.button("Clear").clicked() {
+                self.timeline_cache.clear();
+            }
+            timeline_cache_ui(ui, &self.timeline_cache.fights());
+        });
+    }
+}
+
+fn timeline_cache_ui(ui: &mut egui::Ui, fights: &[CachedFight]) {
+    egui::ScrollArea::vertical().id_source("timeline_cache").show(ui, |ui| {
+        for (i, fight) in fights.iter().enumerate() {
+            let downtime = fight.downtime.iter()
+                .map(|(begin, end)| format!("{:.0}s - {:.0}s", begin, end))
+                .collect::<Vec<_>>()
+                .join(", ");
+            ui.strong(format!("Kill time {:.0}s", fight.kill_time));
+            ui.label(format!("Downtime: {}", if downtime.is_empty() { "none".to_string() } else { downtime }));
+            ui.label(format!(
+                "{} buff windows, precast {}, refresh before downtime {}",
+                fight.buffs, fight.precast, fight.refresh_before,
+            ));
+            egui::Grid::new(("timeline_cache", i)).striped(true).show(ui, |ui| {
+                ui.strong("GCD");
+                ui.strong("Dosis");
+                ui.strong("Eukrasian Dosis");
+                ui.strong("Phlegma");
+                ui.end_row();
+                for (j, (gcd, _)) in fight.timelines.iter().enumerate() {
+                    ui.label(format!("{:.2}", *gcd as f64 / 100.0));
+                    ui.label(fight.count(j, SGEAction::Dosis).to_string());
+                    ui.label(fight.count(j, SGEAction::Edosis).to_string());
+                    ui.label(fight.count(j, SGEAction::Phlegma).to_string());
+                    ui.end_row();
+                }
+            });
+            ui.separator();
+        }
+    });
+}
+
+fn phlegma_plan_ui(ui: &mut egui::Ui, plan: &[PhlegmaCast]) {
+    egui::ScrollArea::vertical().id_source("phlegma_plan").show(ui, |ui| {
+        egui::Grid::new("phlegma_plan").striped(true).show(ui, |ui| {
+            ui.strong("Time");
+            ui.strong("Charges");
+            ui.strong("Reason");
+            ui.end_row();
+            for cast in plan {
+                ui.label(format!("{:.1}s", cast.instant));
+                ui.label(cast.charges.to_string());
+                ui.label(cast.reason.to_string());
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn edosis_plan_ui(ui: &mut egui::Ui, plan: &[EdosisSegment]) {
+    egui::ScrollArea::vertical().id_source("edosis_plan").show(ui, |ui| {
+        egui::Grid::new("edosis_plan").striped(true).show(ui, |ui| {
+            for segment in plan {
+                ui.strong(format!("{:.1}s - {:.1}s", segment.start, segment.end));
+                if segment.refreshes.is_empty() {
+                    ui.label("Not worth refreshing");
+                }
+                ui.end_row();
+                for refresh in segment.refreshes.iter() {
+                    ui.label(format!("{:.1}s", refresh.instant));
+                    ui.label(refresh.cycle.to_string());
+                    ui.end_row();
+                }
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "job;name;buff;magnitude;opener_magnitude;duration;cooldown;opener;alignment\n";
+
+    fn parse(line: &str) -> eyre::Result<Vec<RaidBuff>> {
+        parse_raid_buffs(format!("{}{}\n", HEADER, line).as_bytes())
+    }
+
+    #[test]
+    fn bundled_raid_buffs_load() {
+        let raid_buffs = load_raid_buffs().unwrap();
+        assert!(raid_buffs.iter().any(|raid_buff| raid_buff.alignment == Alignment::Window));
+        let bard_songs = raid_buffs.iter()
+            .filter(|raid_buff| raid_buff.job == "Bard" && raid_buff.alignment == Alignment::Sequence)
+            .count();
+        assert_eq!(bard_songs, 3);
+        assert_eq!(raid_buff_jobs(&raid_buffs)[0], "Bard");
+    }
+
+    #[test]
+    fn raid_buff_alignment_enforced() {
+        assert!(parse("Job;Buff;damage;0.05;;20;120;5;window").is_ok());
+        // A sequence doesn't need a cooldown, a window would come back immediately without one
+        assert!(parse("Job;Buff;damage;0.05;;20;;5;sequence").is_ok());
+        assert!(parse("Job;Buff;damage;0.05;;20;;5;window").is_err());
+        assert!(parse("Job;Buff;damage;0.05;;0;120;5;window").is_err());
+        assert!(parse("Job;Buff;damage;0.05;;20;120;5;whenever").is_err());
+    }
+}
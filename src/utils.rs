@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Unit<const NUMERATOR: u32, const DENOMINATOR: u32>(pub u32);
 
 impl<const NUMERATOR: u32, const DENOMINATOR: u32> Unit<NUMERATOR, DENOMINATOR> {
@@ -5,20 +6,3 @@ impl<const NUMERATOR: u32, const DENOMINATOR: u32> Unit<NUMERATOR, DENOMINATOR>
         self.0 as f64 * NUMERATOR as f64 / DENOMINATOR as f64 
     }
 }
-
-pub trait Scalable {
-    fn scale<const NUMERATOR: u32, const DENOMINATOR: u32>(self, unit: Unit<NUMERATOR, DENOMINATOR>) -> Self;
-}
-
-impl Scalable for u32 {
-    fn scale<const NUMERATOR: u32, const DENOMINATOR: u32>(self, unit: Unit<NUMERATOR, DENOMINATOR>) -> Self {
-        (self * unit.0 * NUMERATOR) / DENOMINATOR
-    }
-}
-
-impl Scalable for f64 {
-    fn scale<const NUMERATOR: u32, const DENOMINATOR: u32>(self, unit: Unit<NUMERATOR, DENOMINATOR>) -> Self {
-        self * (unit.0 * NUMERATOR / DENOMINATOR) as f64
-    }
-}
-